@@ -1,14 +1,52 @@
 // IMPORTANT: DO NOT ADD ANY COMMENTS
 
-pub fn prefix_match(seq: &[u8], bc: &[u8], mismatches: usize) -> bool {
-    if bc.len() > seq.len() { return false; }
+pub enum BarcodeHit { NoMatch, Unique(usize), Ambiguous }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Anchor { Prefix, Suffix }
+
+pub fn prefix_mismatches(seq: &[u8], bc: &[u8], mismatches: usize) -> Option<usize> {
+    if bc.len() > seq.len() { return None; }
     let mut mm = 0usize;
     for i in 0..bc.len() {
         let b = bc[i];
         if b == b'N' { continue; }
-        if b != seq[i] { mm += 1; if mm > mismatches { return false; } }
+        if b != seq[i] { mm += 1; if mm > mismatches { return None; } }
     }
-    true
+    Some(mm)
+}
+
+pub fn prefix_match(seq: &[u8], bc: &[u8], mismatches: usize) -> bool { prefix_mismatches(seq, bc, mismatches).is_some() }
+
+pub fn best_prefix_match(seq: &[u8], bcs: &[&[u8]], mismatches: usize) -> BarcodeHit {
+    let mut best: Option<(usize, usize)> = None;
+    let mut tied = false;
+    for (i, bc) in bcs.iter().enumerate() {
+        if let Some(mm) = prefix_mismatches(seq, bc, mismatches) {
+            match best {
+                Some((_, b)) if mm > b => {}
+                Some((_, b)) if mm == b => tied = true,
+                _ => { best = Some((i, mm)); tied = false; }
+            }
+        }
+    }
+    match best { None => BarcodeHit::NoMatch, Some(_) if tied => BarcodeHit::Ambiguous, Some((i, _)) => BarcodeHit::Unique(i) }
+}
+
+pub fn prefix_log_likelihood(seq: &[u8], qual: &[u8], bc: &[u8], phred_base: u8) -> Option<f64> {
+    if bc.len() > seq.len() || bc.len() > qual.len() { return None; }
+    Some(bc.iter().enumerate().filter(|(_, &b)| b != b'N').map(|(i, &b)| {
+        let e = 10f64.powf(-f64::from(qual[i].saturating_sub(phred_base)) / 10.0).clamp(1e-6, 0.75);
+        if !matches!(seq[i], b'A' | b'C' | b'G' | b'T') { 0.25f64.ln() } else if seq[i] == b { (1.0 - e).ln() } else { (e / 3.0).ln() }
+    }).sum())
+}
+
+pub fn best_posterior_match(seq: &[u8], qual: &[u8], bcs: &[&[u8]], mismatches: usize, phred_base: u8) -> Option<(usize, f64)> {
+    let lls: Vec<(usize, f64)> = bcs.iter().enumerate().filter_map(|(i, bc)| prefix_log_likelihood(seq, qual, bc, phred_base).map(|ll| (i, ll))).collect();
+    let &(best, best_ll) = lls.iter().max_by(|a, b| a.1.total_cmp(&b.1))?;
+    prefix_mismatches(seq, bcs[best], mismatches)?;
+    let total: f64 = lls.iter().map(|(_, ll)| (ll - best_ll).exp()).sum();
+    Some((best, 1.0 / total))
 }
 
 pub fn suffix_match(seq: &[u8], bc: &[u8], mismatches: usize) -> bool {
@@ -27,10 +65,194 @@ pub fn extract_umi_from_suffix(seq: &[u8], bc: &[u8]) -> Option<Vec<u8>> {
     if bc.len() > seq.len() { return None; }
     let offset = seq.len() - bc.len();
     let mut umi_positions = Vec::new();
-    for i in 0..bc.len() { if bc[i] == b'N' { umi_positions.push(offset + i); } }
+    for (i, &b) in bc.iter().enumerate() { if b == b'N' { umi_positions.push(offset + i); } }
     if umi_positions.is_empty() { return Some(Vec::new()); }
     if *umi_positions.iter().min().unwrap() < offset { return None; }
     let mut umi = Vec::with_capacity(umi_positions.len());
     for &p in umi_positions.iter() { umi.push(seq[p]); }
     Some(umi)
-}
\ No newline at end of file
+}
+pub fn extract_umi_from_prefix(seq: &[u8], bc: &[u8]) -> Option<Vec<u8>> {
+    if bc.len() > seq.len() { return None; }
+    let mut umi = Vec::new();
+    for (i, &b) in bc.iter().enumerate() { if b == b'N' { umi.push(seq[i]); } }
+    Some(umi)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EditMatch { pub distance: usize, pub start: usize, pub end: usize, pub columns: Vec<Option<usize>> }
+
+fn prefix_edit(seq: &[u8], bc: &[u8], max_edits: usize) -> Option<(usize, usize, Vec<Option<usize>>)> {
+    let m = bc.len();
+    let w = seq.len().min(m + max_edits);
+    let mut d = vec![vec![0usize; w + 1]; m + 1];
+    for (i, row) in d.iter_mut().enumerate() { row[0] = i; }
+    for (j, v) in d[0].iter_mut().enumerate() { *v = j; }
+    for i in 1..=m {
+        for j in 1..=w {
+            let cost = usize::from(bc[i - 1] != b'N' && bc[i - 1] != seq[j - 1]);
+            d[i][j] = (d[i - 1][j - 1] + cost).min(d[i - 1][j] + 1).min(d[i][j - 1] + 1);
+        }
+    }
+    let end = (0..=w).min_by_key(|&j| (d[m][j], j.abs_diff(m), j))?;
+    if d[m][end] > max_edits { return None; }
+    let mut columns = vec![None; m];
+    let (mut i, mut j) = (m, end);
+    while i > 0 {
+        let cost = if j > 0 { usize::from(bc[i - 1] != b'N' && bc[i - 1] != seq[j - 1]) } else { 0 };
+        if j > 0 && d[i][j] == d[i - 1][j - 1] + cost { columns[i - 1] = Some(j - 1); i -= 1; j -= 1; }
+        else if d[i][j] == d[i - 1][j] + 1 { i -= 1; }
+        else { j -= 1; }
+    }
+    Some((d[m][end], end, columns))
+}
+
+pub fn edit_match(seq: &[u8], bc: &[u8], max_edits: usize, anchor: Anchor) -> Option<EditMatch> {
+    match anchor {
+        Anchor::Prefix => prefix_edit(seq, bc, max_edits).map(|(distance, end, columns)| EditMatch { distance, start: 0, end, columns }),
+        Anchor::Suffix => {
+            let rseq: Vec<u8> = seq.iter().rev().copied().collect();
+            let rbc: Vec<u8> = bc.iter().rev().copied().collect();
+            let n = seq.len();
+            prefix_edit(&rseq, &rbc, max_edits).map(|(distance, end, columns)| {
+                EditMatch { distance, start: n - end, end: n, columns: columns.into_iter().rev().map(|c| c.map(|c| n - 1 - c)).collect() }
+            })
+        }
+    }
+}
+
+pub fn best_edit_match(seq: &[u8], bcs: &[&[u8]], max_edits: usize, anchor: Anchor) -> BarcodeHit {
+    let mut best: Option<(usize, usize)> = None;
+    let mut tied = false;
+    for (i, bc) in bcs.iter().enumerate() {
+        if let Some(hit) = edit_match(seq, bc, max_edits, anchor) {
+            match best {
+                Some((_, b)) if hit.distance > b => {}
+                Some((_, b)) if hit.distance == b => tied = true,
+                _ => { best = Some((i, hit.distance)); tied = false; }
+            }
+        }
+    }
+    match best { None => BarcodeHit::NoMatch, Some(_) if tied => BarcodeHit::Ambiguous, Some((i, _)) => BarcodeHit::Unique(i) }
+}
+
+pub fn extract_umi_from_edit(seq: &[u8], bc: &[u8], hit: &EditMatch) -> Vec<u8> {
+    bc.iter().zip(hit.columns.iter()).filter(|(&b, _)| b == b'N').filter_map(|(_, c)| c.map(|c| seq[c])).collect()
+}
+
+pub fn tso_trim_index(seq: &[u8], tso: &[u8], max_edits: usize, max_g: usize) -> Option<usize> {
+    let core = &tso[..tso.iter().rposition(|&b| b != b'G').map_or(0, |i| i + 1)];
+    if core.is_empty() { return None; }
+    let hit = edit_match(seq, core, max_edits, Anchor::Prefix)?;
+    Some(hit.end + seq[hit.end..].iter().take(max_g).take_while(|&&b| b == b'G').count())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdapterMatch { pub start: usize, pub stop: usize, pub adapter_len: usize, pub errors: usize }
+
+pub fn align_back_adapter(seq: &[u8], adapter: &[u8], error_rate: f64, min_overlap: usize) -> Option<AdapterMatch> {
+    let m = adapter.len();
+    let n = seq.len();
+    if m == 0 || n == 0 { return None; }
+    let allowed = |len: usize| (len as f64 * error_rate).floor() as usize;
+    let mut best: Option<AdapterMatch> = None;
+    let mut consider = |cand: AdapterMatch| {
+        let better = match &best {
+            None => true,
+            Some(b) => (cand.adapter_len, std::cmp::Reverse(cand.errors), std::cmp::Reverse(cand.start)) > (b.adapter_len, std::cmp::Reverse(b.errors), std::cmp::Reverse(b.start)),
+        };
+        if better { best = Some(cand); }
+    };
+    let mut cost = vec![0usize; n + 1];
+    let mut origin: Vec<usize> = (0..=n).collect();
+    let mut next_cost = vec![0usize; n + 1];
+    let mut next_origin = vec![0usize; n + 1];
+    for i in 1..=m {
+        let a = adapter[i - 1];
+        next_cost[0] = i;
+        next_origin[0] = 0;
+        for j in 1..=n {
+            let sub = if a == b'N' || a == seq[j - 1] { 0 } else { 1 };
+            let diag = cost[j - 1] + sub;
+            let up = cost[j] + 1;
+            let left = next_cost[j - 1] + 1;
+            if diag <= up && diag <= left { next_cost[j] = diag; next_origin[j] = origin[j - 1]; }
+            else if up <= left { next_cost[j] = up; next_origin[j] = origin[j]; }
+            else { next_cost[j] = left; next_origin[j] = next_origin[j - 1]; }
+        }
+        std::mem::swap(&mut cost, &mut next_cost);
+        std::mem::swap(&mut origin, &mut next_origin);
+        if i >= min_overlap.max(1) && i < m && cost[n] <= allowed(i) && origin[n] < n { consider(AdapterMatch { start: origin[n], stop: n, adapter_len: i, errors: cost[n] }); }
+    }
+    for j in 1..=n {
+        if cost[j] <= allowed(m) { consider(AdapterMatch { start: origin[j], stop: j, adapter_len: m, errors: cost[j] }); }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADAPTER: &[u8] = b"AGATCGGAAGAGCGGTTCAG";
+
+    #[test]
+    fn test_back_adapter_full_internal() {
+        let seq = b"ACGTACGTACAGATCGGAAGAGCGGTTCAGTTTT";
+        let hit = align_back_adapter(seq, ADAPTER, 0.1, 3).unwrap();
+        assert_eq!((hit.start, hit.adapter_len, hit.errors), (10, 20, 0));
+    }
+
+    #[test]
+    fn test_back_adapter_partial_and_errors() {
+        let hit = align_back_adapter(b"CCCCCCCCCCAGATC", ADAPTER, 0.1, 3).unwrap();
+        assert_eq!((hit.start, hit.adapter_len), (10, 5));
+        let hit = align_back_adapter(b"CCCCAGATCGGTAGAGCGGTTCAG", ADAPTER, 0.1, 3).unwrap();
+        assert_eq!((hit.start, hit.errors), (4, 1));
+        let hit = align_back_adapter(b"CCCCAGATCGGAGAGCGGTTCAG", ADAPTER, 0.1, 3).unwrap();
+        assert_eq!((hit.start, hit.errors), (4, 1));
+    }
+
+    #[test]
+    fn test_edit_match_reports_barcode_end() {
+        let hit = edit_match(b"ACGTCGGGG", b"ACGTAC", 1, Anchor::Prefix).unwrap();
+        assert_eq!((hit.distance, hit.end), (1, 5));
+        let hit = edit_match(b"ACCGTATGGGG", b"ACGTAT", 1, Anchor::Prefix).unwrap();
+        assert_eq!((hit.distance, hit.end), (1, 7));
+        let hit = edit_match(b"ACGTATGGGG", b"ACGTAT", 1, Anchor::Prefix).unwrap();
+        assert_eq!((hit.distance, hit.end), (0, 6));
+        assert!(edit_match(b"AGGTTTGGGG", b"ACGTAT", 1, Anchor::Prefix).is_none());
+        let hit = edit_match(b"GGGGACTAT", b"ACGTAT", 1, Anchor::Suffix).unwrap();
+        assert_eq!((hit.distance, hit.start), (1, 4));
+        let hit = edit_match(b"CAGTCATGGTT", b"AGTNNNGG", 1, Anchor::Prefix).unwrap();
+        assert_eq!((hit.distance, hit.end), (1, 9));
+        assert_eq!(extract_umi_from_edit(b"CAGTCATGGTT", b"AGTNNNGG", &hit), b"CAT".to_vec());
+    }
+
+    #[test]
+    fn test_posterior_uses_base_qualities() {
+        let bcs: [&[u8]; 2] = [b"ACGT", b"ACGA"];
+        let (i, p) = best_posterior_match(b"ACGTGG", b"IIIIII", &bcs, 1, 33).unwrap();
+        assert_eq!(i, 0);
+        assert!(p > 0.999);
+        let (i, p) = best_posterior_match(b"ACGTGG", b"III#II", &bcs, 1, 33).unwrap();
+        assert_eq!(i, 0);
+        assert!(p < 0.7);
+        assert!(best_posterior_match(b"TTTTGG", b"IIIIII", &bcs, 1, 33).is_none());
+    }
+
+    #[test]
+    fn test_back_adapter_min_overlap() {
+        assert!(align_back_adapter(b"CCCCCCCCAG", ADAPTER, 0.1, 3).is_none());
+        assert!(align_back_adapter(b"CCCCCCCCCC", ADAPTER, 0.1, 3).is_none());
+    }
+
+    #[test]
+    fn test_tso_trim_tolerates_g_runs_and_errors() {
+        let tso = b"AAGCAGTGGTATCAACGCAGAGTACATGGG";
+        assert_eq!(tso_trim_index(b"AAGCAGTGGTATCAACGCAGAGTACATGGGGGCTTA", tso, 2, 5), Some(32));
+        assert_eq!(tso_trim_index(b"AAGCAGTGGTATCACGCAGAGTACATGCTTA", tso, 2, 5), Some(27));
+        assert_eq!(tso_trim_index(b"AAGCAGTGGTATCAACGCAGAGTACATGGGGGGGGCTTA", tso, 2, 5), Some(32));
+        assert_eq!(tso_trim_index(b"CTTACTTACTTACTTACTTACTTACTTA", tso, 2, 5), None);
+    }
+}
@@ -1,4 +1,11 @@
 use clap::Parser;
-use ultraplex_rs::cli::{Args, run};
+use ultraplex_rs::cli::{Cli, Command, run, validate};
 
-fn main() -> anyhow::Result<()> { env_logger::init(); let args = Args::parse(); run(args) }
\ No newline at end of file
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    match Cli::parse() {
+        Cli { command: Some(Command::Validate(args)), .. } => validate(args),
+        Cli { args: Some(args), .. } => run(args),
+        Cli { args: None, .. } => unreachable!(),
+    }
+}
@@ -2,12 +2,15 @@ use std::fs;
 use std::path::PathBuf;
 use std::io::Write;
 use clap::CommandFactory;
-use ultraplex_rs::cli::Args;
+use ultraplex_rs::cli::Cli;
 
 fn main() -> anyhow::Result<()> {
-    let mut cmd = Args::command();
+    let mut cmd = Cli::command();
     let mut buf = Vec::new();
     cmd.write_help(&mut buf).unwrap();
+    buf.extend_from_slice(b"\n");
+    cmd.build();
+    cmd.find_subcommand_mut("validate").unwrap().write_help(&mut buf).unwrap();
     let help = String::from_utf8(buf).unwrap();
     let manifest_dir = env!("CARGO_MANIFEST_DIR");
     let mut readme_path = PathBuf::from(manifest_dir);
@@ -1,176 +1,650 @@
 // IMPORTANT: DO NOT ADD ANY COMMENTS
 
-use clap::Parser;
-use crate::trim::{quality_trim_index, nextseq_trim_index};
-use crate::demux::{read_barcodes_csv, create_writers, write_fastq_record, open_fastx, rev_comp, check_n_position};
-use crate::align::{prefix_match, suffix_match, extract_umi_from_suffix};
+use clap::{Parser, Subcommand, ValueEnum};
+use crate::trim::{poly_tail_trim_index, QualityTrimmer, RunningSum, NextSeq, Leading, Trailing, SlidingWindow, MinMeanQuality, TrimChain};
+use crate::demux::{BarcodeSet, OutputFormat, index_key, rev_comp, detect_quality_base, casava_index, casava_umi, Writers, MATE_SUFFIXES, read_barcodes_csv, create_writers, get_writer, write_fastq_record, open_fastx, check_n_position, read_name_stem};
+use crate::index::BarcodeSetIndex;
+use crate::align::{Anchor, BarcodeHit, align_back_adapter, best_edit_match, best_posterior_match, edit_match, extract_umi_from_edit, extract_umi_from_prefix, extract_umi_from_suffix, tso_trim_index};
+use crate::stats::DemuxStats;
+use crate::filter::{FilterReason, ReadFilter};
+use crate::validate::validate_barcodes;
+use crate::read_structure::{Extracted, ReadStructure, SegmentKind};
+use crate::samplesheet::{looks_like_sample_sheet, read_sample_sheet};
+use crate::error::UltraplexError;
+use crate::compress::BlockBudget;
+use crate::sam::{AuxTags, unmapped_flag, write_sam_record, write_bam_record};
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
 use rayon::prelude::*;
 use rayon::ThreadPoolBuilder;
 use anyhow::Result;
 
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum RecordFormat { Fastq, Sam, Bam }
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum SheetFormat { Auto, Ultraplex, Illumina }
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum QualityBase {
+    Auto,
+    #[value(name = "33")]
+    Phred33,
+    #[value(name = "64")]
+    Phred64,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum TrimMode {
+    Cutadapt,
+    Nextseq,
+    #[value(name = "sliding_window")]
+    SlidingWindow,
+    None,
+}
+
+impl QualityBase {
+    pub fn offset(self) -> u8 { if self == QualityBase::Phred64 { 64 } else { 33 } }
+}
+
 #[derive(Parser, Debug, Clone)]
 pub struct Args {
     #[arg(short = 'i', long = "inputfastq", help = "输入 FASTQ 文件路径，支持 .fastq 与 .fastq.gz；推荐 gzip 压缩")]
     pub inputfastq: String,
     #[arg(short = 'd', long = "directory", default_value = "", help = "输出目录，仅用于写出结果文件；不批量读取目录内文件")]
     pub directory: String,
-    #[arg(short = 'b', long = "barcodes", default_value = "", help = "条码 CSV。首列 5’ 条码，后续列为链接的 3’ 条码；支持 :样本名")]
+    #[arg(short = 'b', long = "barcodes", default_value = "", help = "条码 CSV。首列 5’ 条码，后续列为链接的 3’ 条码；支持 :样本名；也可为 Illumina SampleSheet.csv")]
     pub barcodes: String,
+    #[arg(long = "sheet_format", value_enum, default_value_t = SheetFormat::Auto, help = "条码表格式：auto（按内容识别）、ultraplex 或 illumina（[Data]/[BCLConvert_Data] 中的 Sample_ID 与 Inline_5p/Inline_3p 列）")]
+    pub sheet_format: SheetFormat,
     #[arg(short = 'o', long = "outputprefix", default_value = "demux", help = "输出前缀，用于命名 ultraplex_<prefix>_<key>.fastq[.gz]")]
     pub outputprefix: String,
     #[arg(long = "nextseq", default_value_t = false, help = "启用 NextSeq 风格的质量修剪（主要针对 3’ 端低质位）")]
     pub nextseq: bool,
-    #[arg(long = "gzip", default_value_t = false, help = "以 .fastq.gz 格式写出结果文件")]
+    #[arg(long = "gzip", default_value_t = false, help = "以 .fastq.gz 格式写出结果文件（按块并行压缩，使用 --threads 个线程）")]
     pub gzip: bool,
+    #[arg(long = "bgzip", default_value_t = false, conflicts_with = "gzip", help = "以 BGZF 格式写出 .fastq.gz（可被 samtools fqidx 等直接索引）")]
+    pub bgzip: bool,
+    #[arg(long = "output_format", value_enum, default_value_t = RecordFormat::Fastq, help = "输出格式：fastq（UMI 写入 header 的 rbc:）或未比对的 sam/bam（UMI 写入 RX/QX，条码写入 BC/CB，成对读交错写入同一文件）")]
+    pub output_format: RecordFormat,
+    #[arg(long = "compression_level", default_value_t = 6, help = "gzip/BGZF 压缩级别（0-9）")]
+    pub compression_level: u32,
     #[arg(long = "three_prime_only", default_value_t = false, help = "启用 3’ 条码末端匹配与 UMI 抽取（结合 5’ 前缀）")]
     pub three_prime_only: bool,
-    #[arg(short = 'I', long = "input_2", default_value = "", help = "成对测序第二个 FASTQ 路径（预留；后续扩展）")]
+    #[arg(short = 'I', long = "input_2", default_value = "", help = "成对测序第二个 FASTQ 路径；与 -i 同步读取，输出 _Fwd/_Rev 成对文件")]
     pub input_2: String,
+    #[arg(long = "barcode_mate", default_value_t = 1, help = "成对模式下检测条码的读（1 为 -i，2 为 -I）")]
+    pub barcode_mate: usize,
+    #[arg(long = "index_1", default_value = "", help = "I1 索引读 FASTQ；与 -i 同步读取，与条码表 index 列匹配，并与行内条码组合为样本键")]
+    pub index_1: String,
+    #[arg(long = "index_2", default_value = "", help = "I2 索引读 FASTQ；与条码表 index2 列匹配（需同时给出 --index_1）")]
+    pub index_2: String,
+    #[arg(long = "indexmismatches", default_value_t = 1, help = "每个索引读匹配允许的错配数")]
+    pub indexmismatches: usize,
+    #[arg(long = "index2mismatches", help = "I2 索引匹配允许的错配数（默认同 --indexmismatches）")]
+    pub index2mismatches: Option<usize>,
+    #[arg(long = "header_barcodes", default_value_t = false, help = "从 Casava 1.8 header（如 1:N:0:ACGTACGT+TTGCAAGG）读取索引并与条码表 index/index2 列匹配（双索引按 + 拆分），读名第 8 字段的 UMI 一并抽取")]
+    pub header_barcodes: bool,
+    #[arg(long = "read_structure", default_value = "", help = "-i 读的 fgbio 风格读结构（如 4M8B6M+T）：T 模板、B 样本条码（与条码表首列匹配）、M UMI、S 跳过；+ 表示可变长度")]
+    pub read_structure: String,
+    #[arg(long = "read_structure_2", default_value = "", help = "-I 读的读结构（如 +T10B）；仅给出其一时另一读视为 +T")]
+    pub read_structure_2: String,
     #[arg(short = 'M', long = "threeprimemismatches", default_value_t = 0, help = "3’ 条码末端匹配允许的错配数")]
     pub threeprimemismatches: usize,
+    #[arg(short = 'm', long = "fiveprimemismatches", default_value_t = 0, help = "5’ 条码前缀匹配允许的错配数；多个条码同为最优时写入 ambiguous")]
+    pub fiveprimemismatches: usize,
+    #[arg(long = "edit_distance", default_value_t = false, help = "5’/3’ 条码改用编辑距离匹配（容忍插入缺失，适用于纳米孔等数据）；-m/-M 作为最大编辑距离，按实际条码末端切除")]
+    pub edit_distance: bool,
+    #[arg(long = "posterior_threshold", conflicts_with = "edit_distance", help = "按碱基质量计算各 5’ 条码的似然并归一化为后验概率，仅当最优条码后验不低于该阈值（如 0.99）时分配，否则写入 ambiguous；仍受 -m 错配上限约束")]
+    pub posterior_threshold: Option<f64>,
+    #[arg(long = "five_prime_window", default_value_t = 0, conflicts_with_all = ["edit_distance", "posterior_threshold"], help = "5’ 条码可起始的最大偏移（0..k），用于可变长 spacer/错位引物；取错配最少、其次最靠前的偏移，并切除至条码末端的全部碱基")]
+    pub five_prime_window: usize,
+    #[arg(long = "detect_orientation", default_value_t = false, help = "正向未匹配时对读的反向互补重新检测条码布局（适用于纳米孔/连接法文库），匹配的读转为标准链方向并记录检测到的链（header 的 strand:+/-，SAM/BAM 的 XS:A）；仅支持单端")]
+    pub detect_orientation: bool,
     #[arg(short = 't', long = "threads", default_value_t = 4, help = "并行处理线程数")]
     pub threads: usize,
-    #[arg(long = "keep_barcode", default_value_t = false, help = "匹配到 3’ 条码后是否保留条码本体在序列中")]
+    #[arg(long = "batch_size", default_value_t = 1024, help = "读取线程每批交给处理线程池的记录数")]
+    pub batch_size: usize,
+    #[arg(long = "keep_barcode", default_value_t = false, help = "匹配到 5’/3’ 条码后是否保留条码本体（含 UMI 位）在序列中")]
     pub keep_barcode: bool,
     #[arg(short = 'l', long = "final_min_length", default_value_t = 0, help = "长度过滤阈值，短于该长度的读将跳过写出")]
     pub final_min_length: usize,
     #[arg(long = "ignore_no_match", default_value_t = false, help = "忽略无匹配的读（不写入 no_match 文件）")]
     pub ignore_no_match: bool,
-    #[arg(short = 'q', long = "phredquality", default_value_t = 30, help = "质量修剪的 Phred 阈值（默认 30，ASCII 偏移 33）")]
+    #[arg(short = 'a', long = "adapter", default_value = "AGATCGGAAGAGCGGTTCAG", help = "3’ 接头序列（-i 读），质量修剪后、3’ 条码检测前切除；空字符串禁用")]
+    pub adapter: String,
+    #[arg(long = "adapter2", default_value = "AGATCGGAAGAGCGTCGTG", help = "成对模式下 -I 读的 3’ 接头序列；空字符串禁用")]
+    pub adapter2: String,
+    #[arg(long = "adapter_error_rate", default_value_t = 0.1, help = "接头比对允许的错误率（错配与插入缺失，按比对长度计）")]
+    pub adapter_error_rate: f64,
+    #[arg(long = "min_adapter_overlap", default_value_t = 3, help = "读末端部分接头的最小重叠长度")]
+    pub min_adapter_overlap: usize,
+    #[arg(short = 'q', long = "phredquality", default_value_t = 30, help = "质量修剪的 Phred 阈值（默认 30，ASCII 偏移见 --quality_base）")]
     pub phredquality: i32,
+    #[arg(long = "quality_base", value_enum, default_value_t = QualityBase::Auto, help = "输入质量编码：auto（检查 -i 前若干条记录判断 Phred+33/+64）、33 或 64")]
+    pub quality_base: QualityBase,
+    #[arg(long = "output_phred33", default_value_t = false, help = "将 Phred+64 输入的输出质量值转换为 Phred+33（SAM/BAM 输出总是转换）")]
+    pub output_phred33: bool,
+    #[arg(long = "trimmer", value_enum, default_value_t = TrimMode::Cutadapt, help = "质量修剪算法：cutadapt（BWA/cutadapt 累积和，两端）、nextseq（同 --nextseq）、sliding_window（Trimmomatic SLIDINGWINDOW，窗口平均质量低于 -q 处截断）或 none")]
+    pub trimmer: TrimMode,
+    #[arg(long = "window_size", default_value_t = 4, help = "sliding_window 的窗口大小")]
+    pub window_size: usize,
+    #[arg(long = "leading", help = "Trimmomatic LEADING：先切除 5’ 端质量低于该值的碱基")]
+    pub leading: Option<i32>,
+    #[arg(long = "trailing", help = "Trimmomatic TRAILING：先切除 3’ 端质量低于该值的碱基")]
+    pub trailing: Option<i32>,
+    #[arg(long = "min_mean_quality", help = "修剪后平均质量低于该值的读（成对时任一读）将被丢弃")]
+    pub min_mean_quality: Option<f64>,
+    #[arg(long = "poly_tail", default_value = "", help = "按序列切除 --barcode_mate 所指读 3’ 端的同聚物尾（如 A 用于 poly-A 文库，G 用于双色化学的 poly-G），在接头切除后、3’ 条码检测前执行；空字符串禁用")]
+    pub poly_tail: String,
+    #[arg(long = "poly_tail_min_length", default_value_t = 10, help = "切除同聚物尾所需的最小长度")]
+    pub poly_tail_min_length: usize,
+    #[arg(long = "poly_tail_error_rate", default_value_t = 0.1, help = "同聚物尾内允许的非同聚碱基比例")]
+    pub poly_tail_error_rate: f64,
+    #[arg(long = "max_n", help = "丢弃 N 碱基数超过该值的读；小于 1 时按读长比例计")]
+    pub max_n: Option<f64>,
+    #[arg(long = "max_expected_errors", help = "丢弃期望错误数（Σ10^(-Q/10)）超过该值的读")]
+    pub max_expected_errors: Option<f64>,
+    #[arg(long = "max_length", help = "丢弃长于该长度的读")]
+    pub max_length: Option<usize>,
+    #[arg(long = "max_dust", help = "丢弃 DUST 低复杂度得分超过该值的读（三核苷酸重复计数，均聚物约为长度的一半）")]
+    pub max_dust: Option<f64>,
+    #[arg(long = "min_entropy", help = "丢弃二核苷酸香农熵（比特，最大 4）低于该值的读")]
+    pub min_entropy: Option<f64>,
+    #[arg(long = "write_filtered", default_value_t = false, help = "将被过滤的读写入 ultraplex_<prefix>_filtered 文件（header 追加 filter:<原因>），而非直接丢弃")]
+    pub write_filtered: bool,
+    #[arg(long = "tso", default_value = "", help = "模板转换寡核苷酸（TSO，如 AAGCAGTGGTATCAACGCAGAGTACATGGG），条码切除后在读 5’ 端按编辑距离检测并切除，其后的 G 串长度可变；空字符串禁用")]
+    pub tso: String,
+    #[arg(long = "tso_mismatches", default_value_t = 2, help = "TSO（不含末端 G）匹配允许的最大编辑距离")]
+    pub tso_mismatches: usize,
+    #[arg(long = "tso_max_g", default_value_t = 5, help = "TSO 核心序列之后最多连带切除的 G 个数")]
+    pub tso_max_g: usize,
 }
 
-pub fn run(args: Args) -> Result<()> {
-    let mut out_dir = std::path::PathBuf::from(&args.directory);
-    if !args.directory.is_empty() && !args.directory.ends_with('/') {
-        out_dir = std::path::PathBuf::from(format!("{}/", args.directory));
+#[derive(Parser, Debug)]
+#[command(name = "ultraplex_rs", args_conflicts_with_subcommands = true, disable_help_subcommand = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub args: Option<Args>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    #[command(about = "检查条码表在给定错配数下是否可区分，并输出报告")]
+    Validate(ValidateArgs),
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ValidateArgs {
+    #[arg(short = 'b', long = "barcodes", help = "条码 CSV 或 Illumina SampleSheet.csv")]
+    pub barcodes: String,
+    #[arg(long = "sheet_format", value_enum, default_value_t = SheetFormat::Auto, help = "条码表格式：auto、ultraplex 或 illumina")]
+    pub sheet_format: SheetFormat,
+    #[arg(short = 'M', long = "threeprimemismatches", default_value_t = 0, help = "计划使用的 3’ 错配数")]
+    pub threeprimemismatches: usize,
+    #[arg(short = 'm', long = "fiveprimemismatches", default_value_t = 0, help = "计划使用的 5’ 错配数")]
+    pub fiveprimemismatches: usize,
+    #[arg(long = "indexmismatches", default_value_t = 1, help = "计划使用的索引读错配数")]
+    pub indexmismatches: usize,
+    #[arg(long = "index2mismatches", help = "计划使用的 I2 索引错配数（默认同 --indexmismatches）")]
+    pub index2mismatches: Option<usize>,
+}
+
+pub fn load_barcodes(path: &str, format: SheetFormat) -> Result<BarcodeSet, UltraplexError> {
+    let illumina = match format {
+        SheetFormat::Illumina => true,
+        SheetFormat::Ultraplex => false,
+        SheetFormat::Auto => looks_like_sample_sheet(&std::fs::read_to_string(path).map_err(|source| UltraplexError::Io { path: path.to_string(), source })?),
+    };
+    if illumina { read_sample_sheet(path) } else { read_barcodes_csv(path) }
+}
+
+pub fn validate(args: ValidateArgs) -> Result<()> {
+    let mut bcs = load_barcodes(&args.barcodes, args.sheet_format)?;
+    bcs.three_p_mismatches = args.threeprimemismatches;
+    bcs.five_p_mismatches = args.fiveprimemismatches;
+    bcs.index_mismatches = args.indexmismatches;
+    bcs.index_2_mismatches = args.index2mismatches.unwrap_or(args.indexmismatches);
+    let report = validate_barcodes(&bcs);
+    print!("{}", report.summary());
+    if !report.is_safe() { anyhow::bail!("barcode set {} is not safe at -m {} -M {} --indexmismatches {}", args.barcodes, args.fiveprimemismatches, args.threeprimemismatches, args.indexmismatches); }
+    Ok(())
+}
+
+type Rec = (Vec<u8>, Vec<u8>, Vec<u8>);
+
+const QUEUE_DEPTH: usize = 4;
+
+const QUALITY_SNIFF_RECORDS: usize = 10_000;
+
+fn owned_record(rec: &needletail::parser::SequenceRecord) -> Rec {
+    (rec.id().to_vec(), rec.seq().to_vec(), rec.qual().map(|q| q.to_vec()).unwrap_or_default())
+}
+
+fn build_trimmer(args: &Args) -> Result<TrimChain> {
+    let mode = match (args.nextseq, args.trimmer) {
+        (true, TrimMode::Cutadapt | TrimMode::Nextseq) => TrimMode::Nextseq,
+        (true, other) => anyhow::bail!("--nextseq conflicts with --trimmer {:?}", other),
+        (false, mode) => mode,
+    };
+    if mode == TrimMode::SlidingWindow && args.window_size == 0 { anyhow::bail!("window_size must be at least 1"); }
+    let mut steps: Vec<Box<dyn QualityTrimmer>> = Vec::new();
+    if let Some(min_quality) = args.leading { steps.push(Box::new(Leading { min_quality })); }
+    if let Some(min_quality) = args.trailing { steps.push(Box::new(Trailing { min_quality })); }
+    match mode {
+        TrimMode::Cutadapt => steps.push(Box::new(RunningSum { cutoff_front: args.phredquality, cutoff_back: args.phredquality })),
+        TrimMode::Nextseq => steps.push(Box::new(NextSeq { cutoff: args.phredquality })),
+        TrimMode::SlidingWindow => steps.push(Box::new(SlidingWindow { window: args.window_size, min_quality: args.phredquality })),
+        TrimMode::None => {}
     }
-    if !out_dir.as_os_str().is_empty() && !out_dir.exists() { std::fs::create_dir_all(&out_dir)?; }
+    if let Some(min_mean) = args.min_mean_quality { steps.push(Box::new(MinMeanQuality { min_mean })); }
+    Ok(TrimChain(steps))
+}
 
-    let mut barcode_set = if !args.barcodes.is_empty() { Some(read_barcodes_csv(&args.barcodes)?) } else { None };
-    if let Some(bcs) = &mut barcode_set { bcs.three_p_mismatches = args.threeprimemismatches; }
-    if args.three_prime_only {
-        if let Some(bcs) = &barcode_set { check_n_position(&bcs.three_prime)?; }
+fn quality_trim(args: &Args, trimmer: &dyn QualityTrimmer, seq: &[u8], qual: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let (start, stop) = trimmer.trim(seq, qual, args.quality_base.offset() as i32)?;
+    Some((seq[start..stop].to_vec(), qual[start..stop].to_vec()))
+}
+
+#[derive(Default)]
+struct Assignment {
+    key: String,
+    umi: Vec<u8>,
+    umi_qual: Vec<u8>,
+    barcode: String,
+    posterior: Option<f64>,
+    low_posterior: bool,
+}
+
+fn locate_barcode(args: &Args, seq: &[u8], qual: &[u8], bc: &[u8], max_edits: usize, anchor: Anchor) -> (usize, usize, Vec<u8>, Vec<u8>) {
+    if args.edit_distance {
+        if let Some(hit) = edit_match(seq, bc, max_edits, anchor) { return (hit.start, hit.end, extract_umi_from_edit(seq, bc, &hit), extract_umi_from_edit(qual, bc, &hit)); }
     }
-    let mut writers = if let Some(bcs) = &barcode_set {
-        create_writers(out_dir.to_str().unwrap_or(""), &args.outputprefix, &bcs.five_prime, args.gzip)?
+    match anchor {
+        Anchor::Prefix => (0, bc.len(), extract_umi_from_prefix(seq, bc).unwrap_or_default(), extract_umi_from_prefix(qual, bc).unwrap_or_default()),
+        Anchor::Suffix => (seq.len() - bc.len(), seq.len(), extract_umi_from_suffix(seq, bc).unwrap_or_default(), extract_umi_from_suffix(qual, bc).unwrap_or_default()),
+    }
+}
+
+fn fixed_bases(bc: &[u8], observed: &[u8]) -> String {
+    let source = if observed.len() == bc.len() { observed } else { bc };
+    bc.iter().zip(source).filter(|(b, _)| **b != b'N').map(|(_, &c)| c as char).collect()
+}
+
+fn assign_barcode(args: &Args, bcs: &BarcodeSet, index: &BarcodeSetIndex, out_seq: &mut Vec<u8>, out_qual: &mut Vec<u8>) -> Assignment {
+    let mut key = String::from("no_match");
+    let mut umi_out = Vec::new();
+    let mut umi_qual = Vec::new();
+    let mut barcode = String::new();
+    let mut posterior = None;
+    if args.three_prime_only {
+        let (hit, offset) = if args.edit_distance {
+            let rc: Vec<&[u8]> = index.linked_five_rc.iter().map(Vec::as_slice).collect();
+            (best_edit_match(out_seq, &rc, bcs.five_p_mismatches, Anchor::Prefix), 0)
+        } else { index.linked_five_rc_index.best_in_window(out_seq, args.five_prime_window) };
+        match hit {
+            BarcodeHit::Unique(i) => {
+                let five_bc = &index.linked_keys[i];
+                let five_rc = &index.linked_five_rc[i];
+                let three = if args.edit_distance {
+                    bcs.linked[five_bc].iter().position(|t| edit_match(out_seq, t.as_bytes(), bcs.three_p_mismatches, Anchor::Suffix).is_some())
+                } else { index.linked_three[i].first(out_seq) };
+                if let Some(j) = three {
+                    let three_bc = &bcs.linked[five_bc][j];
+                    let (_, front, five_umi, five_umi_qual) = locate_barcode(args, &out_seq[offset..], &out_qual[offset..], five_rc, bcs.five_p_mismatches, Anchor::Prefix);
+                    let (cut, back, three_umi, three_umi_qual) = locate_barcode(args, out_seq, out_qual, three_bc.as_bytes(), bcs.three_p_mismatches, Anchor::Suffix);
+                    umi_out = five_umi.into_iter().chain(three_umi).collect();
+                    umi_qual = five_umi_qual.into_iter().chain(three_umi_qual).collect();
+                    barcode = format!("{}-{}", fixed_bases(five_rc, &out_seq[offset..(offset + front).min(out_seq.len())]), fixed_bases(three_bc.as_bytes(), &out_seq[cut..back]));
+                    if !args.keep_barcode {
+                        out_seq.truncate(cut); out_qual.truncate(cut);
+                        let front = (offset + front).min(out_seq.len()); out_seq.drain(..front); out_qual.drain(..front);
+                    }
+                    key = format!("5bc_{}_3bc_{}", five_bc, three_bc);
+                }
+            }
+            BarcodeHit::Ambiguous => key = String::from("ambiguous"),
+            BarcodeHit::NoMatch => {}
+        }
     } else {
-        create_writers(out_dir.to_str().unwrap_or(""), &args.outputprefix, &Vec::new(), args.gzip)?
+        let five = || bcs.five_prime.iter().map(|b| b.as_bytes()).collect::<Vec<&[u8]>>();
+        let (hit, offset) = if let Some(threshold) = args.posterior_threshold {
+            match best_posterior_match(out_seq, out_qual, &five(), bcs.five_p_mismatches, args.quality_base.offset()) {
+                Some((i, p)) => { posterior = Some(p); (if p >= threshold { BarcodeHit::Unique(i) } else { BarcodeHit::Ambiguous }, 0) }
+                None => (BarcodeHit::NoMatch, 0),
+            }
+        } else if args.edit_distance { (best_edit_match(out_seq, &five(), bcs.five_p_mismatches, Anchor::Prefix), 0) } else { index.five_prime.best_in_window(out_seq, args.five_prime_window) };
+        match hit {
+            BarcodeHit::Unique(i) => {
+                let bc = &bcs.five_prime[i];
+                let (start, end, umi, qual) = locate_barcode(args, &out_seq[offset..], &out_qual[offset..], bc.as_bytes(), bcs.five_p_mismatches, Anchor::Prefix);
+                umi_out = umi;
+                umi_qual = qual;
+                barcode = fixed_bases(bc.as_bytes(), &out_seq[offset + start..offset + end]);
+                if !args.keep_barcode { out_seq.drain(..offset + end); out_qual.drain(..offset + end); }
+                key = bc.clone();
+            }
+            BarcodeHit::Ambiguous => key = String::from("ambiguous"),
+            BarcodeHit::NoMatch => {}
+        }
+    }
+    let low_posterior = posterior.is_some() && key == "ambiguous";
+    Assignment { key, umi: umi_out, umi_qual, barcode, posterior, low_posterior }
+}
+
+fn header_indexes<'a>(bcs: &BarcodeSet, head: &'a [u8]) -> Vec<&'a [u8]> {
+    let Some(index) = casava_index(head) else { return Vec::new() };
+    let mut parts = index.splitn(2, |&c| c == b'+');
+    let i1 = parts.next().unwrap_or_default();
+    if !bcs.indexes.iter().any(|(_, i5)| !i5.is_empty()) { return vec![i1]; }
+    parts.next().map_or(Vec::new(), |i2| vec![i1, i2])
+}
+
+fn assign_sample(args: &Args, barcodes: Option<(&BarcodeSet, &BarcodeSetIndex)>, index_seqs: &[&[u8]], out_seq: &mut Vec<u8>, out_qual: &mut Vec<u8>) -> Assignment {
+    let Some((bcs, index)) = barcodes else { return Assignment { key: String::from("no_match"), ..Default::default() } };
+    let index_key = if bcs.indexes.is_empty() { String::new() } else {
+        match index_seqs.split_first().map(|(i1, rest)| index.index_pair(i1, rest.first().copied())) {
+            Some(BarcodeHit::Unique(n)) => index_key(&bcs.indexes[n].0, &bcs.indexes[n].1),
+            Some(BarcodeHit::Ambiguous) => String::from("ambiguous"),
+            _ => String::from("no_match"),
+        }
+    };
+    let mut a = if bcs.five_prime.is_empty() { Assignment::default() } else { assign_barcode(args, bcs, index, out_seq, out_qual) };
+    let parts: Vec<&str> = [index_key.as_str(), a.key.as_str()].into_iter().filter(|k| !k.is_empty()).collect();
+    let key = match parts.iter().find(|k| **k == "no_match" || **k == "ambiguous") {
+        Some(special) => special.to_string(),
+        None => { let key = parts.join("_"); bcs.sample_names.get(&key).cloned().unwrap_or(key) }
     };
+    a.key = if key == "no_match" && args.ignore_no_match { String::from("__skip__") } else { key };
+    if !index_key.is_empty() && a.key != "no_match" && a.key != "ambiguous" {
+        a.barcode = index_seqs.iter().map(|s| String::from_utf8_lossy(s).into_owned()).chain(Some(a.barcode)).filter(|b| !b.is_empty()).collect::<Vec<_>>().join("-");
+    }
+    a
+}
+
+struct Processed {
+    key: String,
+    mates: Vec<Rec>,
+    umi: Vec<u8>,
+    umi_qual: Vec<u8>,
+    barcode: String,
+    filtered: Option<FilterReason>,
+    adapter_trimmed: bool,
+    posterior: Option<f64>,
+    low_posterior: bool,
+    strand: Option<u8>,
+}
+
+struct Trimmed {
+    mates: Vec<Rec>,
+    extracted: Option<Vec<Extracted>>,
+    adapter_trimmed: bool,
+    low_quality: bool,
+}
+
+fn process_read(args: &Args, trimmer: &dyn QualityTrimmer, filter: &ReadFilter, barcodes: Option<(&BarcodeSet, &BarcodeSetIndex)>, structures: &[ReadStructure], fragment: &[Rec]) -> Processed {
+    let unmatched = |p: &Processed| p.key == "no_match" || p.key == "__skip__";
+    let trimmed = trim_strand(args, trimmer, structures, fragment, false);
+    if !args.detect_orientation { return process_strand(args, filter, barcodes, structures, trimmed, fragment); }
+    let flipped = if structures.is_empty() { Trimmed { mates: trimmed.mates.iter().map(|(id, seq, qual)| (id.clone(), rev_comp(seq), qual.iter().rev().copied().collect())).collect(), extracted: None, ..trimmed } } else { trim_strand(args, trimmer, structures, fragment, true) };
+    let mut forward = process_strand(args, filter, barcodes, structures, trimmed, fragment);
+    if unmatched(&forward) {
+        let mut reverse = process_strand(args, filter, barcodes, structures, flipped, fragment);
+        if !unmatched(&reverse) { reverse.strand = Some(b'-'); return reverse; }
+        return forward;
+    }
+    forward.strand = Some(b'+');
+    forward
+}
+
+fn trim_strand(args: &Args, trimmer: &dyn QualityTrimmer, structures: &[ReadStructure], fragment: &[Rec], reverse: bool) -> Trimmed {
+    let flip = |seq: Vec<u8>, qual: Vec<u8>| if reverse { (rev_comp(&seq), qual.into_iter().rev().collect()) } else { (seq, qual) };
+    let mates = &fragment[..if args.input_2.is_empty() { 1 } else { 2 }];
+    let extracted = if structures.is_empty() { None } else { mates.iter().zip(structures).map(|((_, seq, qual), rs)| { let (seq, qual) = flip(seq.clone(), qual.clone()); rs.extract(&seq, &qual) }).collect::<Option<Vec<_>>>() };
+    let mut adapter_trimmed = false;
+    let mut low_quality = false;
+    let mates = mates.iter().enumerate().map(|(i, (id, seq, qual))| {
+        let owned;
+        let (seq, qual): (&[u8], &[u8]) = match &extracted { Some(e) => { owned = flip(e[i].template.clone(), e[i].template_qual.clone()); (&owned.0, &owned.1) } None => (seq, qual) };
+        let (mut s, mut q) = quality_trim(args, trimmer, seq, qual).unwrap_or_else(|| { low_quality = true; (seq.to_vec(), qual.to_vec()) });
+        let adapter = if i == 0 { &args.adapter } else { &args.adapter2 };
+        if let Some(hit) = align_back_adapter(&s, adapter.as_bytes(), args.adapter_error_rate, args.min_adapter_overlap) { s.truncate(hit.start); q.truncate(hit.start); adapter_trimmed = true; }
+        if let Some(&base) = args.poly_tail.as_bytes().first().filter(|_| i == args.barcode_mate - 1) {
+            let stop = poly_tail_trim_index(&s, base, args.poly_tail_min_length, args.poly_tail_error_rate);
+            s.truncate(stop); q.truncate(stop);
+        }
+        let (s, q) = flip(s, q);
+        (id.clone(), s, q)
+    }).collect();
+    Trimmed { mates, extracted, adapter_trimmed, low_quality }
+}
+
+fn process_strand(args: &Args, filter: &ReadFilter, barcodes: Option<(&BarcodeSet, &BarcodeSetIndex)>, structures: &[ReadStructure], trimmed: Trimmed, fragment: &[Rec]) -> Processed {
+    let Trimmed { mates: mut out, extracted, adapter_trimmed, low_quality } = trimmed;
+    let (mates, index_reads) = fragment.split_at(out.len());
+    let index_seqs: Vec<&[u8]> = match barcodes {
+        Some((bcs, _)) if args.header_barcodes => header_indexes(bcs, &mates[0].0),
+        _ => index_reads.iter().map(|r| r.1.as_slice()).collect(),
+    };
+    let Assignment { key, mut umi, mut umi_qual, barcode, posterior, low_posterior } = match &extracted {
+        Some(e) => {
+            let mut bseq: Vec<u8> = e.iter().flat_map(|x| x.sample_barcode.iter().copied()).collect();
+            let mut bqual: Vec<u8> = e.iter().flat_map(|x| x.sample_barcode_qual.iter().copied()).collect();
+            let mut a = assign_sample(args, barcodes, &index_seqs, &mut bseq, &mut bqual);
+            a.umi = e.iter().flat_map(|x| x.umi.iter().copied()).chain(a.umi).collect();
+            a.umi_qual = e.iter().flat_map(|x| x.umi_qual.iter().copied()).chain(a.umi_qual).collect();
+            a
+        }
+        None if !structures.is_empty() => Assignment { key: String::from(if barcodes.is_some() && args.ignore_no_match { "__skip__" } else { "no_match" }), ..Default::default() },
+        None => {
+            let (_, seq, qual) = &mut out[args.barcode_mate - 1];
+            assign_sample(args, barcodes, &index_seqs, seq, qual)
+        }
+    };
+    if !args.tso.is_empty() && barcodes.is_some() && !["no_match", "ambiguous", "__skip__"].contains(&key.as_str()) {
+        let (_, seq, qual) = &mut out[args.barcode_mate - 1];
+        if let Some(cut) = tso_trim_index(seq, args.tso.as_bytes(), args.tso_mismatches, args.tso_max_g) { seq.drain(..cut); qual.drain(..cut); }
+    }
+    if let Some(header_umi) = casava_umi(&mates[0].0).filter(|_| args.header_barcodes) {
+        umi = header_umi.iter().map(|&c| if c == b'+' { b'-' } else { c }).chain(umi).collect();
+        umi_qual.clear();
+    }
+    let filtered = if low_quality { Some(FilterReason::LowQuality) } else {
+        out.iter().find_map(|(_, seq, qual)| filter.check(seq, qual, args.quality_base.offset()))
+            .or_else(|| out.iter().any(|(_, seq, _)| seq.len() < args.final_min_length).then_some(FilterReason::TooShort))
+    };
+    if args.quality_base == QualityBase::Phred64 && (args.output_phred33 || args.output_format != RecordFormat::Fastq) {
+        for q in out.iter_mut().flat_map(|(_, _, q)| q.iter_mut()).chain(umi_qual.iter_mut()) { *q = q.saturating_sub(31); }
+    }
+    if !umi.is_empty() && args.output_format == RecordFormat::Fastq {
+        for (head, _, _) in out.iter_mut() { head.extend_from_slice(b"rbc:"); head.extend_from_slice(&umi); }
+    }
+    Processed { key, mates: out, umi, umi_qual, barcode, filtered, adapter_trimmed, posterior, low_posterior, strand: None }
+}
+
+fn write_results(args: &Args, out_dir: &str, writers: &mut Writers, results: Vec<Processed>) -> Result<()> {
+    let paired = !args.input_2.is_empty();
+    let split_mates = paired && args.output_format == RecordFormat::Fastq;
+    for Processed { key, mut mates, umi, umi_qual, barcode, filtered, strand, .. } in results {
+        if key == "__skip__" { continue; }
+        let key = match filtered { Some(_) if args.write_filtered => String::from("filtered"), Some(_) => continue, None => key };
+        let strand: &[u8] = match &strand { Some(s) => std::slice::from_ref(s), None => &[] };
+        if !strand.is_empty() && args.output_format == RecordFormat::Fastq {
+            for (head, _, _) in mates.iter_mut() { head.extend_from_slice(b" strand:"); head.extend_from_slice(strand); }
+        }
+        if let Some(reason) = filtered.filter(|_| args.output_format == RecordFormat::Fastq) {
+            for (head, _, _) in mates.iter_mut() { head.extend_from_slice(b" filter:"); head.extend_from_slice(reason.name().as_bytes()); }
+        }
+        let tags = AuxTags { umi: &umi, umi_qual: &umi_qual, barcode: barcode.as_bytes(), strand };
+        for (i, (head, out_seq, out_qual)) in mates.iter().enumerate() {
+            let suffix = if split_mates { MATE_SUFFIXES[i] } else { "" };
+            let w: &mut dyn Write = if key == "no_match" {
+                if i == 0 || !split_mates { writers.default.as_mut() } else { writers.default_rev.as_mut().unwrap().as_mut() }
+            } else {
+                get_writer(writers, out_dir, &args.outputprefix, &format!("{}{}", key, suffix))?
+            };
+            match args.output_format {
+                RecordFormat::Fastq => write_fastq_record(w, head, out_seq, out_qual)?,
+                RecordFormat::Sam => write_sam_record(w, read_name_stem(head), unmapped_flag(i, paired), out_seq, out_qual, &tags)?,
+                RecordFormat::Bam => write_bam_record(w, read_name_stem(head), unmapped_flag(i, paired), out_seq, out_qual, &tags)?,
+            }
+        }
+    }
+    Ok(())
+}
 
-    let pool = ThreadPoolBuilder::new().num_threads(args.threads).build().unwrap();
+fn read_batches(args: &Args, tx: SyncSender<Vec<Vec<Rec>>>) -> Result<()> {
     let mut reader = open_fastx(&args.inputfastq)?;
-    let mut chunk: Vec<(Vec<u8>, Vec<u8>, Vec<u8>)> = Vec::with_capacity(1024);
+    let mut others = Vec::new();
+    for path in [&args.input_2, &args.index_1, &args.index_2] {
+        if !path.is_empty() { others.push((path, open_fastx(path)?)); }
+    }
+    let mut chunk: Vec<Vec<Rec>> = Vec::with_capacity(args.batch_size);
+    let mut n_records = 0usize;
     loop {
-        match reader.next() {
-            Some(Ok(rec)) => {
-                let id = rec.id().to_vec();
-                let seq = rec.seq().to_vec();
-                let qual = rec.qual().map(|q| q.to_vec()).unwrap_or_default();
-                chunk.push((id, seq, qual));
-                if chunk.len() >= 1024 {
-                    let results = pool.install(|| {
-                        chunk.par_iter().map(|(id, seq, qual)| {
-                            let qual_str = String::from_utf8(qual.clone()).unwrap_or_default();
-                            let (start, stop) = if args.nextseq {
-                                let idx = nextseq_trim_index(std::str::from_utf8(&seq).unwrap_or(""), &qual_str, args.phredquality, 33);
-                                (0, idx)
-                            } else { quality_trim_index(&qual_str, args.phredquality, args.phredquality, 33) };
-                            let mut out_seq = seq[start..stop].to_vec();
-                            let mut out_qual = qual[start..stop].to_vec();
-                            let mut head = id.clone();
-                            let mut key = String::from("no_match");
-                            if let Some(bcs) = &barcode_set {
-                                let mut matched = false;
-                                if args.three_prime_only {
-                                    for (five_bc, three_bcs) in bcs.linked.clone() {
-                                        let five_rc = rev_comp(five_bc.as_bytes());
-                                        if prefix_match(&out_seq, &five_rc, 0) {
-                                            for three_bc in three_bcs {
-                                                if suffix_match(&out_seq, three_bc.as_bytes(), bcs.three_p_mismatches) {
-                                                    if let Some(umi) = extract_umi_from_suffix(&out_seq, three_bc.as_bytes()) { if !umi.is_empty() { head.extend_from_slice(b"rbc:"); head.extend_from_slice(&umi); } }
-                                                    if !args.keep_barcode { let cut = out_seq.len() - three_bc.len(); out_seq = out_seq[..cut].to_vec(); out_qual = out_qual[..cut].to_vec(); }
-                                                    let combo = format!("5bc_{}_3bc_{}", five_bc, three_bc);
-                                                    key = if let Some(sample) = bcs.sample_names.get(&combo) { sample.clone() } else { combo };
-                                                    matched = true; break;
-                                                }
-                                            }
-                                            if matched { break; }
-                                        }
-                                    }
-                                } else {
-                                    for bc in &bcs.five_prime { if prefix_match(&out_seq, bc.as_bytes(), 0) { key = bc.clone(); matched = true; break; } }
-                                }
-                                if !matched && args.ignore_no_match { key = String::from("__skip__"); }
-                            }
-                            (key, head, out_seq, out_qual)
-                        }).collect::<Vec<(String, Vec<u8>, Vec<u8>, Vec<u8>)>>()
-                    });
-                    for (key, head, out_seq, out_qual) in results {
-                        if key == "__skip__" { continue; }
-                        if out_seq.len() < args.final_min_length { continue; }
-                        if key == "no_match" { write_fastq_record(writers.default.as_mut(), &head, &out_seq, &out_qual)?; } else {
-                            let w = crate::demux::get_writer(&mut writers, out_dir.to_str().unwrap_or(""), &args.outputprefix, &key, args.gzip);
-                            write_fastq_record(w, &head, &out_seq, &out_qual)?;
-                        }
-                    }
-                    chunk.clear();
-                }
-            }
+        let mut mates = Vec::with_capacity(1 + others.len());
+        let done = match reader.next() {
+            Some(Ok(rec)) => { mates.push(owned_record(&rec)); false }
             Some(Err(e)) => return Err(e.into()),
-            None => {
-                if !chunk.is_empty() {
-                    let results = pool.install(|| {
-                        chunk.par_iter().map(|(id, seq, qual)| {
-                            let qual_str = String::from_utf8(qual.clone()).unwrap_or_default();
-                            let (start, stop) = if args.nextseq {
-                                let idx = nextseq_trim_index(std::str::from_utf8(&seq).unwrap_or(""), &qual_str, args.phredquality, 33);
-                                (0, idx)
-                            } else { quality_trim_index(&qual_str, args.phredquality, args.phredquality, 33) };
-                            let mut out_seq = seq[start..stop].to_vec();
-                            let mut out_qual = qual[start..stop].to_vec();
-                            let mut head = id.clone();
-                            let mut key = String::from("no_match");
-                            if let Some(bcs) = &barcode_set {
-                                let mut matched = false;
-                                if args.three_prime_only {
-                                    for (five_bc, three_bcs) in bcs.linked.clone() {
-                                        let five_rc = rev_comp(five_bc.as_bytes());
-                                        if prefix_match(&out_seq, &five_rc, 0) {
-                                            for three_bc in three_bcs {
-                                                if suffix_match(&out_seq, three_bc.as_bytes(), bcs.three_p_mismatches) {
-                                                    if let Some(umi) = extract_umi_from_suffix(&out_seq, three_bc.as_bytes()) { if !umi.is_empty() { head.extend_from_slice(b"rbc:"); head.extend_from_slice(&umi); } }
-                                                    if !args.keep_barcode { let cut = out_seq.len() - three_bc.len(); out_seq = out_seq[..cut].to_vec(); out_qual = out_qual[..cut].to_vec(); }
-                                                    let combo = format!("5bc_{}_3bc_{}", five_bc, three_bc);
-                                                    key = if let Some(sample) = bcs.sample_names.get(&combo) { sample.clone() } else { combo };
-                                                    matched = true; break;
-                                                }
-                                            }
-                                            if matched { break; }
-                                        }
-                                    }
-                                } else {
-                                    for bc in &bcs.five_prime { if prefix_match(&out_seq, bc.as_bytes(), 0) { key = bc.clone(); matched = true; break; } }
-                                }
-                                if !matched && args.ignore_no_match { key = String::from("__skip__"); }
-                            }
-                            (key, head, out_seq, out_qual)
-                        }).collect::<Vec<(String, Vec<u8>, Vec<u8>, Vec<u8>)>>()
-                    });
-                    for (key, head, out_seq, out_qual) in results {
-                        if key == "__skip__" { continue; }
-                        if out_seq.len() < args.final_min_length { continue; }
-                        if key == "no_match" { write_fastq_record(writers.default.as_mut(), &head, &out_seq, &out_qual)?; } else {
-                            let w = crate::demux::get_writer(&mut writers, out_dir.to_str().unwrap_or(""), &args.outputprefix, &key, args.gzip);
-                            write_fastq_record(w, &head, &out_seq, &out_qual)?;
-                        }
+            None => true,
+        };
+        for (path, other) in others.iter_mut() {
+            match (done, other.next()) {
+                (false, Some(Ok(rec))) => {
+                    let mate = owned_record(&rec);
+                    if read_name_stem(&mates[0].0) != read_name_stem(&mate.0) {
+                        anyhow::bail!("read names differ at record {}: {} in {} vs {} in {}", n_records + 1, String::from_utf8_lossy(&mates[0].0), args.inputfastq, String::from_utf8_lossy(&mate.0), path);
                     }
+                    mates.push(mate);
                 }
-                break;
+                (_, Some(Err(e))) => return Err(e.into()),
+                (true, None) => {}
+                (false, None) => anyhow::bail!("{} has more records than {} ({} paired so far)", args.inputfastq, path, n_records),
+                (true, Some(Ok(_))) => anyhow::bail!("{} has more records than {} ({} paired so far)", path, args.inputfastq, n_records),
             }
         }
+        if !done { chunk.push(mates); n_records += 1; }
+        if chunk.len() >= args.batch_size || (done && !chunk.is_empty()) {
+            let full = std::mem::replace(&mut chunk, Vec::with_capacity(args.batch_size));
+            if tx.send(full).is_err() { return Ok(()); }
+        }
+        if done { return Ok(()); }
     }
+}
+
+fn write_batches(args: &Args, out_dir: &str, mut writers: Writers, rx: Receiver<Vec<Processed>>) -> Result<()> {
+    for results in rx { write_results(args, out_dir, &mut writers, results)?; }
     for (_, mut w) in writers.by_barcode.into_iter() { w.flush()?; }
     writers.default.flush()?;
+    if let Some(mut w) = writers.default_rev { w.flush()?; }
+    Ok(())
+}
+
+pub fn run(args: Args) -> Result<()> {
+    let quality_base = match args.quality_base {
+        QualityBase::Auto => if detect_quality_base(&args.inputfastq, QUALITY_SNIFF_RECORDS)? == 64 { QualityBase::Phred64 } else { QualityBase::Phred33 },
+        given => given,
+    };
+    if quality_base == QualityBase::Phred64 { log::info!("{} uses Phred+64 quality encoding", args.inputfastq); }
+    let args = Args { adapter: args.adapter.to_uppercase(), adapter2: args.adapter2.to_uppercase(), poly_tail: args.poly_tail.to_uppercase(), tso: args.tso.to_uppercase(), quality_base, ..args };
+    let mut out_dir = std::path::PathBuf::from(&args.directory);
+    if !args.directory.is_empty() && !args.directory.ends_with('/') {
+        out_dir = std::path::PathBuf::from(format!("{}/", args.directory));
+    }
+    if !out_dir.as_os_str().is_empty() && !out_dir.exists() { std::fs::create_dir_all(&out_dir)?; }
+    let paired = !args.input_2.is_empty();
+    let split_mates = paired && args.output_format == RecordFormat::Fastq;
+    if args.output_format != RecordFormat::Fastq && (args.gzip || args.bgzip) { anyhow::bail!("--gzip/--bgzip apply to fastq output only; bam output is always BGZF-compressed"); }
+    if args.compression_level > 9 { anyhow::bail!("compression_level must be between 0 and 9"); }
+    if args.posterior_threshold.is_some_and(|p| !(0.0..=1.0).contains(&p)) { anyhow::bail!("posterior_threshold must be between 0 and 1"); }
+    if args.detect_orientation && paired { anyhow::bail!("--detect_orientation supports single-end input only"); }
+    if args.posterior_threshold.is_some() && args.three_prime_only { anyhow::bail!("--posterior_threshold applies to 5' barcodes and cannot be combined with --three_prime_only"); }
+    if !args.poly_tail.is_empty() && !matches!(args.poly_tail.as_str(), "A" | "C" | "G" | "T") { anyhow::bail!("--poly_tail must be a single base (A, C, G or T)"); }
+    if !args.tso.is_empty() && args.tso.bytes().all(|b| b == b'G') { anyhow::bail!("--tso must contain bases other than the trailing G run"); }
+    if args.batch_size == 0 { anyhow::bail!("batch_size must be at least 1"); }
+    if args.barcode_mate != 1 && !(paired && args.barcode_mate == 2) { anyhow::bail!("barcode_mate must be 1, or 2 when --input_2 is given"); }
+    if !args.index_2.is_empty() && args.index_1.is_empty() { anyhow::bail!("--index_2 requires --index_1"); }
+
+    let mut barcode_set = if !args.barcodes.is_empty() { Some(load_barcodes(&args.barcodes, args.sheet_format)?) } else { None };
+    if let Some(bcs) = &mut barcode_set { bcs.three_p_mismatches = args.threeprimemismatches; bcs.five_p_mismatches = args.fiveprimemismatches; bcs.index_mismatches = args.indexmismatches; bcs.index_2_mismatches = args.index2mismatches.unwrap_or(args.indexmismatches); }
+    let sheet_indexes = barcode_set.as_ref().map_or((false, false), |bcs| (!bcs.indexes.is_empty(), bcs.indexes.iter().any(|(_, i5)| !i5.is_empty())));
+    if args.header_barcodes {
+        if !args.index_1.is_empty() { anyhow::bail!("--header_barcodes reads indexes from the FASTQ header; do not also give --index_1/--index_2"); }
+        if !sheet_indexes.0 { anyhow::bail!("--header_barcodes needs index (and optionally index2) columns in the barcode sheet"); }
+    } else if sheet_indexes != (!args.index_1.is_empty(), !args.index_2.is_empty()) {
+        anyhow::bail!("--index_1/--index_2 must be given exactly when the barcode sheet has index/index2 columns");
+    }
+    if args.three_prime_only {
+        if let Some(bcs) = &barcode_set { check_n_position(&bcs.three_prime)?; }
+    }
+    if let Some(bcs) = &barcode_set {
+        let report = validate_barcodes(bcs);
+        eprint!("{}", report.summary());
+        if !report.is_safe() { log::warn!("barcode collisions found; reads may be misassigned or sent to ambiguous"); }
+    }
+    let hamming = !args.edit_distance && args.posterior_threshold.is_none();
+    let barcode_index = barcode_set.as_ref().map(|bcs| BarcodeSetIndex::new(bcs, hamming));
+    let trimmer = build_trimmer(&args)?;
+    let filter = ReadFilter { max_n: args.max_n, max_expected_errors: args.max_expected_errors, max_length: args.max_length, max_dust: args.max_dust, min_entropy: args.min_entropy };
+    let structures: Vec<ReadStructure> = if args.read_structure.is_empty() && args.read_structure_2.is_empty() { Vec::new() } else {
+        let mut given = vec![args.read_structure.as_str()];
+        if paired { given.push(args.read_structure_2.as_str()); } else if !args.read_structure_2.is_empty() { anyhow::bail!("--read_structure_2 requires --input_2"); }
+        given.into_iter().map(|s| if s.is_empty() { "+T".parse() } else { s.parse() }).collect::<Result<_, _>>()?
+    };
+    if !structures.is_empty() {
+        if args.three_prime_only { anyhow::bail!("--read_structure cannot be combined with --three_prime_only"); }
+        if barcode_set.as_ref().is_some_and(|bcs| !bcs.five_prime.is_empty()) && !structures.iter().any(|rs| rs.has_kind(SegmentKind::SampleBarcode)) {
+            anyhow::bail!("the read structures have no sample barcode (B) segment to match the barcode sheet against");
+        }
+    }
+    let out_dir_str = out_dir.to_str().unwrap_or("");
+    let pool = Arc::new(ThreadPoolBuilder::new().num_threads(args.threads).build()?);
+    let budget = Arc::new(BlockBudget::new(pool.current_num_threads() * 2));
+    let format = if args.output_format == RecordFormat::Sam { OutputFormat::Sam }
+        else if args.output_format == RecordFormat::Bam { OutputFormat::Bam { level: args.compression_level, pool: pool.clone(), budget } }
+        else if args.bgzip { OutputFormat::Bgzf { level: args.compression_level, pool: pool.clone(), budget } }
+        else if args.gzip { OutputFormat::Gzip { level: args.compression_level, pool: pool.clone(), budget } }
+        else { OutputFormat::Fastq };
+    let writers = if let Some(bcs) = &barcode_set {
+        let raw: Vec<String> = if bcs.five_prime.is_empty() { bcs.indexes.iter().map(|(i7, i5)| index_key(i7, i5)).collect() } else if bcs.indexes.is_empty() { bcs.five_prime.clone() } else { Vec::new() };
+        let keys: Vec<String> = raw.iter().map(|k| bcs.sample_names.get(k).unwrap_or(k).clone()).collect();
+        create_writers(out_dir_str, &args.outputprefix, &keys, format, split_mates)?
+    } else {
+        create_writers(out_dir_str, &args.outputprefix, &Vec::new(), format, split_mates)?
+    };
+
+    let (batch_tx, batch_rx) = sync_channel::<Vec<Vec<Rec>>>(QUEUE_DEPTH);
+    let (out_tx, out_rx) = sync_channel::<Vec<Processed>>(QUEUE_DEPTH);
+    let stats = std::thread::scope(|scope| -> Result<DemuxStats> {
+        let args = &args;
+        let trimmer = &trimmer;
+        let filter = &filter;
+        let barcodes = barcode_set.as_ref().zip(barcode_index.as_ref());
+        let reader = scope.spawn(move || read_batches(args, batch_tx));
+        let writer = scope.spawn(move || write_batches(args, out_dir_str, writers, out_rx));
+        let mut stats = DemuxStats::default();
+        for chunk in batch_rx {
+            let (results, chunk_stats) = pool.install(|| {
+                let results = chunk.par_iter().map(|mates| process_read(args, trimmer, filter, barcodes, &structures, mates)).collect::<Vec<Processed>>();
+                let chunk_stats = results.par_iter()
+                    .fold(DemuxStats::default, |mut st, p| { st.record(&p.key, p.filtered, p.adapter_trimmed, p.posterior, p.low_posterior, p.strand == Some(b'-')); st })
+                    .reduce(DemuxStats::default, DemuxStats::merge);
+                (results, chunk_stats)
+            });
+            stats = stats.merge(chunk_stats);
+            if out_tx.send(results).is_err() { break; }
+        }
+        drop(out_tx);
+        let read_result = reader.join().map_err(|_| anyhow::anyhow!("reader thread panicked"))?;
+        let write_result = writer.join().map_err(|_| anyhow::anyhow!("writer thread panicked"))?;
+        write_result?;
+        read_result?;
+        Ok(stats)
+    })?;
+    std::fs::write(out_dir.join(format!("ultraplex_{}.stats.json", args.outputprefix)), stats.to_json())?;
+    eprint!("{}", stats.summary());
     Ok(())
 }
@@ -0,0 +1,158 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::{Compression, Crc};
+use rayon::ThreadPool;
+
+pub const GZ_BLOCK_SIZE: usize = 128 * 1024;
+pub const BGZF_BLOCK_SIZE: usize = 0xff00;
+pub const BGZF_EOF: [u8; 28] = [0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, b'B', b'C', 0x02, 0, 0x1b, 0, 0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+pub fn gzip_member(data: &[u8], level: u32) -> io::Result<Vec<u8>> {
+    let mut enc = GzEncoder::new(Vec::with_capacity(data.len() / 3 + 64), Compression::new(level));
+    enc.write_all(data)?;
+    enc.finish()
+}
+
+fn deflate_raw(data: &[u8], level: u32) -> io::Result<Vec<u8>> {
+    let mut enc = DeflateEncoder::new(Vec::with_capacity(data.len() / 3 + 64), Compression::new(level));
+    enc.write_all(data)?;
+    enc.finish()
+}
+
+pub fn bgzf_block(data: &[u8], level: u32) -> io::Result<Vec<u8>> {
+    if data.len() > BGZF_BLOCK_SIZE { return Err(io::Error::new(io::ErrorKind::InvalidInput, "BGZF block input too large")); }
+    let mut cdata = deflate_raw(data, level)?;
+    if cdata.len() + 26 > 0x10000 { cdata = deflate_raw(data, 0)?; }
+    let mut crc = Crc::new();
+    crc.update(data);
+    let bsize = (cdata.len() + 25) as u16;
+    let mut out = Vec::with_capacity(cdata.len() + 26);
+    out.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0, b'B', b'C', 0x02, 0]);
+    out.extend_from_slice(&bsize.to_le_bytes());
+    out.extend_from_slice(&cdata);
+    out.extend_from_slice(&crc.sum().to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    Ok(out)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockCodec { Gzip, Bgzf }
+
+impl BlockCodec {
+    pub fn block_size(self) -> usize { match self { BlockCodec::Gzip => GZ_BLOCK_SIZE, BlockCodec::Bgzf => BGZF_BLOCK_SIZE } }
+
+    fn compress(self, data: &[u8], level: u32) -> io::Result<Vec<u8>> {
+        match self { BlockCodec::Gzip => gzip_member(data, level), BlockCodec::Bgzf => bgzf_block(data, level) }
+    }
+}
+
+pub struct BlockBudget { limit: usize, in_flight: AtomicUsize }
+
+impl BlockBudget {
+    pub fn new(limit: usize) -> Self { BlockBudget { limit: limit.max(1), in_flight: AtomicUsize::new(0) } }
+
+    pub fn in_flight(&self) -> usize { self.in_flight.load(Ordering::Acquire) }
+
+    fn exceeded(&self) -> bool { self.in_flight() > self.limit }
+}
+
+pub struct ParGzWriter<W: Write> {
+    inner: W,
+    codec: BlockCodec,
+    buf: Vec<u8>,
+    level: u32,
+    pool: Arc<ThreadPool>,
+    pending: VecDeque<Receiver<io::Result<Vec<u8>>>>,
+    budget: Arc<BlockBudget>,
+    started: bool,
+    terminated: bool,
+}
+
+impl<W: Write> ParGzWriter<W> {
+    pub fn new(inner: W, codec: BlockCodec, level: u32, pool: Arc<ThreadPool>, budget: Arc<BlockBudget>) -> Self {
+        ParGzWriter { inner, codec, buf: Vec::with_capacity(codec.block_size()), level, pool, pending: VecDeque::new(), budget, started: false, terminated: false }
+    }
+
+    fn submit(&mut self, block: Vec<u8>) -> io::Result<()> {
+        let (tx, rx) = sync_channel(1);
+        let (codec, level) = (self.codec, self.level);
+        self.pool.spawn(move || { let _ = tx.send(codec.compress(&block, level)); });
+        self.pending.push_back(rx);
+        self.budget.in_flight.fetch_add(1, Ordering::AcqRel);
+        self.started = true;
+        while !self.pending.is_empty() && self.budget.exceeded() { self.write_front()?; }
+        Ok(())
+    }
+
+    fn write_front(&mut self) -> io::Result<()> {
+        if let Some(rx) = self.pending.pop_front() {
+            self.budget.in_flight.fetch_sub(1, Ordering::AcqRel);
+            let member = rx.recv().map_err(|_| io::Error::other("compression worker disconnected"))??;
+            self.inner.write_all(&member)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ParGzWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        self.terminated = false;
+        let bs = self.codec.block_size();
+        while self.buf.len() >= bs {
+            let rest = self.buf.split_off(bs);
+            let block = std::mem::replace(&mut self.buf, rest);
+            self.submit(block)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() || (self.codec == BlockCodec::Gzip && !self.started) {
+            let block = std::mem::take(&mut self.buf);
+            self.submit(block)?;
+        }
+        while !self.pending.is_empty() { self.write_front()?; }
+        if self.codec == BlockCodec::Bgzf && !self.terminated { self.inner.write_all(&BGZF_EOF)?; }
+        self.terminated = true;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for ParGzWriter<W> {
+    fn drop(&mut self) { let _ = self.flush(); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn test_many_writers_share_one_block_budget() {
+        let pool = Arc::new(rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap());
+        let budget = Arc::new(BlockBudget::new(8));
+        let mut outputs: Vec<Vec<u8>> = vec![Vec::new(); 200];
+        let chunk: Vec<u8> = (0..GZ_BLOCK_SIZE as u32).map(|i| b"ACGT\n"[(i % 5) as usize]).collect();
+        {
+            let mut writers: Vec<ParGzWriter<&mut Vec<u8>>> = outputs.iter_mut().map(|o| ParGzWriter::new(o, BlockCodec::Gzip, 1, pool.clone(), budget.clone())).collect();
+            for _ in 0..3 {
+                for w in writers.iter_mut() {
+                    w.write_all(&chunk).unwrap();
+                    assert!(budget.in_flight() <= 8);
+                }
+            }
+        }
+        assert_eq!(budget.in_flight(), 0);
+        for out in outputs {
+            let mut raw = Vec::new();
+            MultiGzDecoder::new(&out[..]).read_to_end(&mut raw).unwrap();
+            assert_eq!(raw.len(), 3 * chunk.len());
+        }
+    }
+}
@@ -5,9 +5,12 @@ use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::PathBuf;
 use csv::ReaderBuilder;
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use std::sync::Arc;
 use needletail::parse_fastx_file;
+use rayon::ThreadPool;
+use crate::compress::{BlockBudget, BlockCodec, ParGzWriter};
+use crate::sam::{bam_header, sam_header};
+use crate::error::UltraplexError;
 
 #[derive(Clone)]
 pub struct BarcodeSet {
@@ -15,70 +18,130 @@ pub struct BarcodeSet {
     pub three_prime: Vec<String>,
     pub linked: std::collections::HashMap<String, Vec<String>>,
     pub sample_names: std::collections::HashMap<String, String>,
+    pub indexes: Vec<(String, String)>,
     pub three_p_mismatches: usize,
+    pub five_p_mismatches: usize,
+    pub index_mismatches: usize,
+    pub index_2_mismatches: usize,
 }
 
-pub fn read_barcodes_csv(path: &str) -> anyhow::Result<BarcodeSet> {
-    let mut rdr = ReaderBuilder::new().has_headers(false).from_path(path)?;
+pub fn index_key(index_1: &str, index_2: &str) -> String {
+    if index_2.is_empty() { index_1.to_string() } else { format!("{}+{}", index_1, index_2) }
+}
+
+pub(crate) fn check_bases(path: &str, line: u64, bc: &str) -> Result<(), UltraplexError> {
+    match bc.chars().find(|c| !matches!(c, 'A' | 'C' | 'G' | 'T' | 'N')) {
+        Some(base) => Err(UltraplexError::InvalidBase { path: path.to_string(), line, barcode: bc.to_string(), base }),
+        None => Ok(()),
+    }
+}
+
+pub fn read_barcodes_csv(path: &str) -> Result<BarcodeSet, UltraplexError> {
+    let csv_err = |source| UltraplexError::Csv { path: path.to_string(), source };
+    let mut rdr = ReaderBuilder::new().has_headers(false).flexible(true).from_path(path).map_err(csv_err)?;
     let mut five_prime = Vec::new();
     let mut three_prime = Vec::new();
     let mut linked: HashMap<String, Vec<String>> = HashMap::new();
     let mut sample_names: HashMap<String, String> = HashMap::new();
     let mut fivelength: Option<usize> = None;
+    let mut five_lines: HashMap<String, u64> = HashMap::new();
+    let mut name_lines: HashMap<String, u64> = HashMap::new();
     for result in rdr.records() {
-        let rec = result?;
+        let rec = result.map_err(csv_err)?;
+        let line = rec.position().map(|p| p.line()).unwrap_or(0);
         let first = rec.get(0).unwrap_or("").trim().to_uppercase();
         if first.is_empty() { continue; }
         let five_bc = first.split(':').next().unwrap().to_string();
-        if fivelength.is_none() { fivelength = Some(five_bc.replace('N', "").len()); } else {
-            assert_eq!(fivelength.unwrap(), five_bc.replace('N', "").len());
+        check_bases(path, line, &five_bc)?;
+        let found = five_bc.replace('N', "").len();
+        match fivelength {
+            None => fivelength = Some(found),
+            Some(expected) if expected != found => return Err(UltraplexError::InconsistentBarcodeLength { path: path.to_string(), line, barcode: five_bc, expected, found }),
+            Some(_) => {}
         }
+        if let Some(&first_line) = five_lines.get(&five_bc) { return Err(UltraplexError::DuplicateBarcode { path: path.to_string(), line, barcode: five_bc, first_line }); }
+        five_lines.insert(five_bc.clone(), line);
         five_prime.push(five_bc.clone());
-        let mut three_list = Vec::new();
+        let mut three_list: Vec<(String, usize)> = Vec::new();
         for i in 1..rec.len() {
             let col = rec.get(i).unwrap_or("").trim();
             if col.is_empty() { continue; }
             let parts: Vec<&str> = col.split(':').collect();
             let bc = parts[0].to_uppercase();
+            check_bases(path, line, &bc)?;
+            if let Some(&(_, first_column)) = three_list.iter().find(|(b, _)| *b == bc) { return Err(UltraplexError::DuplicateThreePrimeBarcode { path: path.to_string(), line, barcode: bc, column: i + 1, first_column }); }
             three_prime.push(bc.clone());
-            three_list.push(bc.clone());
+            three_list.push((bc.clone(), i + 1));
             if parts.len() > 1 && !parts[1].is_empty() {
-                sample_names.insert(format!("5bc_{}_3bc_{}", five_bc, bc), parts[1].to_string());
+                let name = parts[1].to_string();
+                if let Some(&first_line) = name_lines.get(&name) { return Err(UltraplexError::DuplicateSampleName { path: path.to_string(), line, name, first_line }); }
+                name_lines.insert(name.clone(), line);
+                sample_names.insert(format!("5bc_{}_3bc_{}", five_bc, bc), name);
             }
         }
-        if !three_list.is_empty() { linked.insert(five_bc, three_list); }
+        if !three_list.is_empty() { linked.insert(five_bc, three_list.into_iter().map(|(bc, _)| bc).collect()); }
     }
-    five_prime.sort(); five_prime.dedup();
+    five_prime.sort();
     three_prime.sort(); three_prime.dedup();
-    Ok(BarcodeSet { five_prime, three_prime, linked, sample_names, three_p_mismatches: 0 })
+    Ok(BarcodeSet { five_prime, three_prime, linked, sample_names, indexes: Vec::new(), three_p_mismatches: 0, five_p_mismatches: 0, index_mismatches: 0, index_2_mismatches: 0 })
+}
+
+pub const MATE_SUFFIXES: [&str; 2] = ["_Fwd", "_Rev"];
+
+#[derive(Clone)]
+pub enum OutputFormat {
+    Fastq,
+    Gzip { level: u32, pool: Arc<ThreadPool>, budget: Arc<BlockBudget> },
+    Bgzf { level: u32, pool: Arc<ThreadPool>, budget: Arc<BlockBudget> },
+    Sam,
+    Bam { level: u32, pool: Arc<ThreadPool>, budget: Arc<BlockBudget> },
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self { OutputFormat::Fastq => "fastq", OutputFormat::Gzip { .. } | OutputFormat::Bgzf { .. } => "fastq.gz", OutputFormat::Sam => "sam", OutputFormat::Bam { .. } => "bam" }
+    }
 }
 
 pub struct Writers {
     pub default: Box<dyn Write + Send>,
+    pub default_rev: Option<Box<dyn Write + Send>>,
     pub by_barcode: HashMap<String, Box<dyn Write + Send>>,
+    pub format: OutputFormat,
 }
 
-pub fn create_writers(output_dir: &str, prefix: &str, barcodes: &[String], gz: bool) -> anyhow::Result<Writers> {
-    let dir = if output_dir.is_empty() { PathBuf::from(".") } else { PathBuf::from(output_dir) };
-    if !dir.as_os_str().is_empty() && !dir.exists() { std::fs::create_dir_all(&dir)?; }
-    let ext = if gz { "fastq.gz" } else { "fastq" };
-    let default_file = File::create(dir.join(format!("ultraplex_{}_no_match.{}", prefix, ext)))?;
-    let default: Box<dyn Write + Send> = if gz {
-        Box::new(GzEncoder::new(BufWriter::new(default_file), Compression::default()))
-    } else {
-        Box::new(BufWriter::new(default_file))
+fn open_writer(path: PathBuf, format: &OutputFormat) -> Result<Box<dyn Write + Send>, UltraplexError> {
+    let io_err = |source| UltraplexError::Io { path: path.display().to_string(), source };
+    let f = File::create(&path).map_err(io_err)?;
+    let mut w: Box<dyn Write + Send> = match format {
+        OutputFormat::Fastq | OutputFormat::Sam => Box::new(BufWriter::new(f)),
+        OutputFormat::Gzip { level, pool, budget } => Box::new(ParGzWriter::new(f, BlockCodec::Gzip, *level, pool.clone(), budget.clone())),
+        OutputFormat::Bgzf { level, pool, budget } | OutputFormat::Bam { level, pool, budget } => Box::new(ParGzWriter::new(f, BlockCodec::Bgzf, *level, pool.clone(), budget.clone())),
     };
+    match format {
+        OutputFormat::Sam => w.write_all(&sam_header()).map_err(io_err)?,
+        OutputFormat::Bam { .. } => w.write_all(&bam_header()).map_err(io_err)?,
+        _ => {}
+    }
+    Ok(w)
+}
+
+pub fn create_writers(output_dir: &str, prefix: &str, barcodes: &[String], format: OutputFormat, paired: bool) -> Result<Writers, UltraplexError> {
+    let dir = if output_dir.is_empty() { PathBuf::from(".") } else { PathBuf::from(output_dir) };
+    if !dir.as_os_str().is_empty() && !dir.exists() { std::fs::create_dir_all(&dir).map_err(|source| UltraplexError::Io { path: dir.display().to_string(), source })?; }
+    let ext = format.extension();
+    let suffixes: &[&str] = if paired { &MATE_SUFFIXES } else { &[""] };
+    let default = open_writer(dir.join(format!("ultraplex_{}_no_match{}.{}", prefix, suffixes[0], ext)), &format)?;
+    let default_rev = if paired { Some(open_writer(dir.join(format!("ultraplex_{}_no_match{}.{}", prefix, MATE_SUFFIXES[1], ext)), &format)?) } else { None };
     let mut by_barcode = HashMap::new();
     for bc in barcodes {
-        let f = File::create(dir.join(format!("ultraplex_{}_{}.{}", prefix, bc, ext)))?;
-        let w: Box<dyn Write + Send> = if gz {
-            Box::new(GzEncoder::new(BufWriter::new(f), Compression::default()))
-        } else {
-            Box::new(BufWriter::new(f))
-        };
-        by_barcode.insert(bc.clone(), w);
+        for suffix in suffixes {
+            let key = format!("{}{}", bc, suffix);
+            let w = open_writer(dir.join(format!("ultraplex_{}_{}.{}", prefix, key, ext)), &format)?;
+            by_barcode.insert(key, w);
+        }
     }
-    Ok(Writers { default, by_barcode })
+    Ok(Writers { default, default_rev, by_barcode, format })
 }
 
 pub fn write_fastq_record(w: &mut dyn Write, name: &[u8], seq: &[u8], qual: &[u8]) -> anyhow::Result<()> {
@@ -94,15 +157,43 @@ pub fn write_fastq_record(w: &mut dyn Write, name: &[u8], seq: &[u8], qual: &[u8
 
 pub fn open_fastx(path: &str) -> anyhow::Result<Box<dyn needletail::FastxReader>> { Ok(parse_fastx_file(path)?) }
 
-pub fn get_writer<'a>(writers: &'a mut Writers, output_dir: &str, prefix: &str, key: &str, gz: bool) -> &'a mut (dyn Write + Send) {
+pub fn detect_quality_base(path: &str, max_records: usize) -> anyhow::Result<u8> {
+    let mut reader = open_fastx(path)?;
+    let (mut lo, mut hi) = (u8::MAX, 0u8);
+    for _ in 0..max_records {
+        let Some(rec) = reader.next() else { break };
+        for &c in rec?.qual().unwrap_or_default() { lo = lo.min(c); hi = hi.max(c); }
+    }
+    Ok(if lo >= 64 && hi > 75 { 64 } else { 33 })
+}
+
+pub fn get_writer<'a>(writers: &'a mut Writers, output_dir: &str, prefix: &str, key: &str) -> Result<&'a mut (dyn Write + Send), UltraplexError> {
     if !writers.by_barcode.contains_key(key) {
         let dir = if output_dir.is_empty() { PathBuf::from(".") } else { PathBuf::from(output_dir) };
-        let ext = if gz { "fastq.gz" } else { "fastq" };
-        let f = File::create(dir.join(format!("ultraplex_{}_{}.{}", prefix, key, ext))).expect("create writer");
-        let w: Box<dyn Write + Send> = if gz { Box::new(GzEncoder::new(BufWriter::new(f), Compression::default())) } else { Box::new(BufWriter::new(f)) };
+        let w = open_writer(dir.join(format!("ultraplex_{}_{}.{}", prefix, key, writers.format.extension())), &writers.format)?;
         writers.by_barcode.insert(key.to_string(), w);
     }
-    writers.by_barcode.get_mut(key).unwrap().as_mut()
+    Ok(writers.by_barcode.get_mut(key).unwrap().as_mut())
+}
+
+pub fn read_name_stem(id: &[u8]) -> &[u8] {
+    let end = id.iter().position(|c| c.is_ascii_whitespace()).unwrap_or(id.len());
+    let name = &id[..end];
+    if name.len() > 2 && name[name.len() - 2] == b'/' && (name[name.len() - 1] == b'1' || name[name.len() - 1] == b'2') { &name[..name.len() - 2] } else { name }
+}
+
+pub fn casava_index(id: &[u8]) -> Option<&[u8]> {
+    let start = id.iter().position(|c| c.is_ascii_whitespace())?;
+    let comment = id[start..].trim_ascii_start();
+    let comment = &comment[..comment.iter().position(|c| c.is_ascii_whitespace()).unwrap_or(comment.len())];
+    let fields: Vec<&[u8]> = comment.split(|&c| c == b':').collect();
+    if fields.len() == 4 && !fields[3].is_empty() { Some(fields[3]) } else { None }
+}
+
+pub fn casava_umi(id: &[u8]) -> Option<&[u8]> {
+    let name = &id[..id.iter().position(|c| c.is_ascii_whitespace()).unwrap_or(id.len())];
+    let fields: Vec<&[u8]> = name.split(|&c| c == b':').collect();
+    if fields.len() == 8 && !fields[7].is_empty() { Some(fields[7]) } else { None }
 }
 
 pub fn rev_comp(seq: &[u8]) -> Vec<u8> {
@@ -0,0 +1,23 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum UltraplexError {
+    #[error("{path}:{line}: 5' barcode {barcode} has {found} non-N bases, expected {expected} like the first barcode")]
+    InconsistentBarcodeLength { path: String, line: u64, barcode: String, expected: usize, found: usize },
+    #[error("{path}:{line}: barcode {barcode} contains invalid base '{base}' (allowed: A, C, G, T, N)")]
+    InvalidBase { path: String, line: u64, barcode: String, base: char },
+    #[error("{path}:{line}: sample name {name} is already used on line {first_line}")]
+    DuplicateSampleName { path: String, line: u64, name: String, first_line: u64 },
+    #[error("{path}:{line}: barcode {barcode} is already listed on line {first_line}")]
+    DuplicateBarcode { path: String, line: u64, barcode: String, first_line: u64 },
+    #[error("{path}:{line}: 3' barcode {barcode} is listed in both column {first_column} and column {column} of this row")]
+    DuplicateThreePrimeBarcode { path: String, line: u64, barcode: String, column: usize, first_column: usize },
+    #[error("{path}:{line}: {message}")]
+    InvalidSampleSheet { path: String, line: u64, message: String },
+    #[error("invalid read structure {structure}: {message}")]
+    InvalidReadStructure { structure: String, message: String },
+    #[error("{path}: {source}")]
+    Csv { path: String, #[source] source: csv::Error },
+    #[error("{path}: {source}")]
+    Io { path: String, #[source] source: std::io::Error },
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterReason { TooShort, LowQuality, TooManyN, ExpectedErrors, TooLong, LowComplexity }
+
+impl FilterReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            FilterReason::TooShort => "too_short",
+            FilterReason::LowQuality => "low_quality",
+            FilterReason::TooManyN => "too_many_n",
+            FilterReason::ExpectedErrors => "expected_errors",
+            FilterReason::TooLong => "too_long",
+            FilterReason::LowComplexity => "low_complexity",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReadFilter {
+    pub max_n: Option<f64>,
+    pub max_expected_errors: Option<f64>,
+    pub max_length: Option<usize>,
+    pub max_dust: Option<f64>,
+    pub min_entropy: Option<f64>,
+}
+
+impl ReadFilter {
+    pub fn check(&self, seq: &[u8], qual: &[u8], base: u8) -> Option<FilterReason> {
+        if let Some(max_n) = self.max_n {
+            let limit = if max_n >= 1.0 { max_n } else { max_n * seq.len() as f64 };
+            if n_count(seq) as f64 > limit { return Some(FilterReason::TooManyN); }
+        }
+        if self.max_expected_errors.is_some_and(|m| expected_errors(qual, base) > m) { return Some(FilterReason::ExpectedErrors); }
+        if self.max_length.is_some_and(|m| seq.len() > m) { return Some(FilterReason::TooLong); }
+        if self.max_dust.is_some_and(|m| dust_score(seq) > m) || self.min_entropy.is_some_and(|m| dinucleotide_entropy(seq) < m) { return Some(FilterReason::LowComplexity); }
+        None
+    }
+}
+
+pub fn n_count(seq: &[u8]) -> usize { seq.iter().filter(|&&b| b == b'N' || b == b'n').count() }
+
+pub fn expected_errors(qual: &[u8], base: u8) -> f64 {
+    qual.iter().map(|&q| 10f64.powf(-(q.saturating_sub(base) as f64) / 10.0)).sum()
+}
+
+pub fn dust_score(seq: &[u8]) -> f64 {
+    if seq.len() < 4 { return 0.0; }
+    let mut counts: HashMap<&[u8], usize> = HashMap::new();
+    for t in seq.windows(3) { *counts.entry(t).or_insert(0) += 1; }
+    let total: usize = counts.values().map(|&c| c * (c - 1) / 2).sum();
+    total as f64 / (seq.len() - 3) as f64
+}
+
+pub fn dinucleotide_entropy(seq: &[u8]) -> f64 {
+    if seq.len() < 2 { return 0.0; }
+    let mut counts: HashMap<&[u8], usize> = HashMap::new();
+    for d in seq.windows(2) { *counts.entry(d).or_insert(0) += 1; }
+    let n = (seq.len() - 1) as f64;
+    counts.values().map(|&c| { let p = c as f64 / n; -p * p.log2() }).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters_by_reason() {
+        let filter = ReadFilter { max_n: Some(0.1), max_expected_errors: Some(1.0), max_length: Some(12), max_dust: Some(2.0), min_entropy: Some(2.0) };
+        assert_eq!(filter.check(b"ACGTTGCAAGCT", b"IIIIIIIIIIII", 33), None);
+        assert_eq!(filter.check(b"ACNTTGCNAGCT", b"IIIIIIIIIIII", 33), Some(FilterReason::TooManyN));
+        assert_eq!(filter.check(b"ACGTTGCAAGCT", b"IIIIIIIII###", 33), Some(FilterReason::ExpectedErrors));
+        assert_eq!(filter.check(b"ACGTTGCAAGCTA", b"IIIIIIIIIIIII", 33), Some(FilterReason::TooLong));
+        assert_eq!(filter.check(b"AAAAAAAAAAAA", b"IIIIIIIIIIII", 33), Some(FilterReason::LowComplexity));
+        assert_eq!(filter.check(b"ACACACACACAC", b"IIIIIIIIIIII", 33), Some(FilterReason::LowComplexity));
+    }
+
+    #[test]
+    fn test_complexity_scores() {
+        assert_eq!(dust_score(b"AAAAAA"), 2.0);
+        assert_eq!(dinucleotide_entropy(b"ACAC"), 0.9182958340544896);
+        assert!(dinucleotide_entropy(b"ACGTTGCAAGCT") > 3.0);
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use crate::align::{Anchor, BarcodeHit, prefix_mismatches};
+use crate::demux::{BarcodeSet, rev_comp};
+
+const ALPHABET: [u8; 5] = [b'A', b'C', b'G', b'T', b'N'];
+
+const MAX_INDEX_KEYS: usize = 1 << 22;
+
+#[derive(Debug, Clone, Copy)]
+struct Entry { best: usize, best_mm: usize, tied: bool, first: usize }
+
+#[derive(Debug, Clone)]
+struct LayoutTable { len: usize, positions: Vec<usize>, map: HashMap<Vec<u8>, Entry> }
+
+#[derive(Debug, Clone)]
+pub struct BarcodeIndex { anchor: Anchor, tables: Vec<LayoutTable>, linear: Option<(Vec<Vec<u8>>, usize)> }
+
+fn canonical(b: u8) -> u8 { if matches!(b, b'A' | b'C' | b'G' | b'T') { b } else { b'N' } }
+
+fn neighbourhood_size(len: usize, mismatches: usize) -> usize {
+    let mut choose = 1usize;
+    let mut total = 0usize;
+    for k in 0..=mismatches.min(len) {
+        if let Some(next) = choose.saturating_mul(len + 1 - k).checked_div(k) { choose = next; }
+        total = total.saturating_add(choose.saturating_mul(4usize.saturating_pow(k as u32)));
+    }
+    total
+}
+
+fn neighbours(key: &mut Vec<u8>, from: usize, left: usize, mm: usize, out: &mut Vec<(Vec<u8>, usize)>) {
+    out.push((key.clone(), mm));
+    if left == 0 { return; }
+    for p in from..key.len() {
+        let orig = key[p];
+        for &c in ALPHABET.iter() {
+            if c == orig { continue; }
+            key[p] = c;
+            neighbours(key, p + 1, left - 1, mm + 1, out);
+        }
+        key[p] = orig;
+    }
+}
+
+impl BarcodeIndex {
+    pub fn new(barcodes: &[&[u8]], anchor: Anchor, mismatches: usize) -> Self {
+        let keys = barcodes.iter().fold(0usize, |n, bc| n.saturating_add(neighbourhood_size(bc.iter().filter(|&&b| b != b'N').count(), mismatches)));
+        if keys > MAX_INDEX_KEYS {
+            log::info!("{} mismatch neighbours exceed the index limit; matching {} barcodes by linear scan", keys, barcodes.len());
+            return BarcodeIndex { anchor, tables: Vec::new(), linear: Some((barcodes.iter().map(|b| b.to_vec()).collect(), mismatches)) };
+        }
+        let mut tables: Vec<LayoutTable> = Vec::new();
+        for (i, bc) in barcodes.iter().enumerate() {
+            let positions: Vec<usize> = bc.iter().enumerate().filter(|(_, &b)| b != b'N').map(|(p, _)| p).collect();
+            let t = match tables.iter().position(|t| t.len == bc.len() && t.positions == positions) {
+                Some(t) => t,
+                None => { tables.push(LayoutTable { len: bc.len(), positions: positions.clone(), map: HashMap::new() }); tables.len() - 1 }
+            };
+            let mut key: Vec<u8> = positions.iter().map(|&p| canonical(bc[p])).collect();
+            let mut keys = Vec::new();
+            neighbours(&mut key, 0, mismatches, 0, &mut keys);
+            for (k, mm) in keys {
+                tables[t].map.entry(k)
+                    .and_modify(|e| {
+                        e.first = e.first.min(i);
+                        if mm < e.best_mm { e.best = i; e.best_mm = mm; e.tied = false; } else if mm == e.best_mm { e.tied = true; }
+                    })
+                    .or_insert(Entry { best: i, best_mm: mm, tied: false, first: i });
+            }
+        }
+        BarcodeIndex { anchor, tables, linear: None }
+    }
+
+    fn linear_entry(&self, seq: &[u8]) -> Option<Entry> {
+        let (barcodes, mismatches) = self.linear.as_ref()?;
+        let mut entry: Option<Entry> = None;
+        for (i, bc) in barcodes.iter().enumerate().filter(|(_, bc)| bc.len() <= seq.len()) {
+            let window = match self.anchor { Anchor::Prefix => seq, Anchor::Suffix => &seq[seq.len() - bc.len()..] };
+            let Some(mm) = prefix_mismatches(window, bc, *mismatches) else { continue };
+            match entry.as_mut() {
+                None => entry = Some(Entry { best: i, best_mm: mm, tied: false, first: i }),
+                Some(e) if mm < e.best_mm => { e.best = i; e.best_mm = mm; e.tied = false; }
+                Some(e) if mm == e.best_mm => e.tied = true,
+                Some(_) => {}
+            }
+        }
+        entry
+    }
+
+    fn lookups<'a>(&'a self, seq: &'a [u8]) -> impl Iterator<Item = Entry> + 'a {
+        self.tables.iter().filter(move |t| t.len <= seq.len()).filter_map(move |t| {
+            let offset = match self.anchor { Anchor::Prefix => 0, Anchor::Suffix => seq.len() - t.len };
+            let key: Vec<u8> = t.positions.iter().map(|&p| canonical(seq[offset + p])).collect();
+            t.map.get(&key).copied()
+        }).chain(self.linear_entry(seq))
+    }
+
+    pub fn best_scored(&self, seq: &[u8]) -> Option<(BarcodeHit, usize)> {
+        let mut best: Option<(usize, usize)> = None;
+        let mut tied = false;
+        for e in self.lookups(seq) {
+            match best {
+                Some((_, b)) if e.best_mm > b => {}
+                Some((_, b)) if e.best_mm == b => tied = true,
+                _ => { best = Some((e.best, e.best_mm)); tied = e.tied; }
+            }
+        }
+        best.map(|(i, mm)| (if tied { BarcodeHit::Ambiguous } else { BarcodeHit::Unique(i) }, mm))
+    }
+
+    pub fn best(&self, seq: &[u8]) -> BarcodeHit { self.best_scored(seq).map_or(BarcodeHit::NoMatch, |(hit, _)| hit) }
+
+    pub fn best_in_window(&self, seq: &[u8], window: usize) -> (BarcodeHit, usize) {
+        let mut best: Option<(BarcodeHit, usize, usize)> = None;
+        for offset in 0..=window.min(seq.len()) {
+            if let Some((hit, mm)) = self.best_scored(&seq[offset..]) {
+                if best.as_ref().is_none_or(|b| mm < b.1) { best = Some((hit, mm, offset)); }
+            }
+        }
+        best.map_or((BarcodeHit::NoMatch, 0), |(hit, _, offset)| (hit, offset))
+    }
+
+    pub fn first(&self, seq: &[u8]) -> Option<usize> { self.lookups(seq).map(|e| e.first).min() }
+}
+
+#[derive(Debug, Clone)]
+pub struct BarcodeSetIndex {
+    pub five_prime: BarcodeIndex,
+    pub linked_keys: Vec<String>,
+    pub linked_five_rc: Vec<Vec<u8>>,
+    pub linked_five_rc_index: BarcodeIndex,
+    pub linked_three: Vec<BarcodeIndex>,
+    pub index_1: BarcodeIndex,
+    pub index_2: BarcodeIndex,
+    pub index_pairs: HashMap<(usize, usize), usize>,
+}
+
+impl BarcodeSetIndex {
+    pub fn new(bcs: &BarcodeSet, inline: bool) -> Self {
+        let five: Vec<&[u8]> = if inline { bcs.five_prime.iter().map(|b| b.as_bytes()).collect() } else { Vec::new() };
+        let mut linked_keys: Vec<String> = bcs.linked.keys().cloned().collect();
+        linked_keys.sort();
+        let linked_five_rc: Vec<Vec<u8>> = linked_keys.iter().map(|k| rev_comp(k.as_bytes())).collect();
+        let rc: Vec<&[u8]> = if inline { linked_five_rc.iter().map(|b| b.as_slice()).collect() } else { Vec::new() };
+        let linked_three = linked_keys.iter().filter(|_| inline).map(|k| {
+            let three: Vec<&[u8]> = bcs.linked[k].iter().map(|b| b.as_bytes()).collect();
+            BarcodeIndex::new(&three, Anchor::Suffix, bcs.three_p_mismatches)
+        }).collect();
+        let mut i7: Vec<&[u8]> = bcs.indexes.iter().map(|(a, _)| a.as_bytes()).collect();
+        let mut i5: Vec<&[u8]> = bcs.indexes.iter().map(|(_, b)| b.as_bytes()).collect();
+        i7.sort(); i7.dedup();
+        i5.sort(); i5.dedup();
+        let index_pairs = bcs.indexes.iter().enumerate().map(|(n, (a, b))| {
+            ((i7.binary_search(&a.as_bytes()).unwrap(), i5.binary_search(&b.as_bytes()).unwrap()), n)
+        }).collect();
+        BarcodeSetIndex {
+            five_prime: BarcodeIndex::new(&five, Anchor::Prefix, bcs.five_p_mismatches),
+            linked_five_rc_index: BarcodeIndex::new(&rc, Anchor::Prefix, bcs.five_p_mismatches),
+            linked_keys, linked_five_rc, linked_three,
+            index_1: BarcodeIndex::new(&i7, Anchor::Prefix, bcs.index_mismatches),
+            index_2: BarcodeIndex::new(&i5, Anchor::Prefix, bcs.index_2_mismatches),
+            index_pairs,
+        }
+    }
+
+    pub fn index_pair(&self, index_1: &[u8], index_2: Option<&[u8]>) -> BarcodeHit {
+        let a = match self.index_1.best(index_1) { BarcodeHit::Unique(a) => a, hit => return hit };
+        let b = match index_2.map(|s| self.index_2.best(s)) { None => 0, Some(BarcodeHit::Unique(b)) => b, Some(hit) => return hit };
+        self.index_pairs.get(&(a, b)).map_or(BarcodeHit::NoMatch, |&n| BarcodeHit::Unique(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::align::{best_prefix_match, suffix_match};
+
+    struct Lcg(u64);
+    impl Lcg {
+        fn next(&mut self) -> u64 { self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407); self.0 >> 33 }
+        fn seq(&mut self, n: usize, alphabet: &[u8]) -> Vec<u8> { (0..n).map(|_| alphabet[(self.next() as usize) % alphabet.len()]).collect() }
+    }
+
+    #[test]
+    fn test_index_matches_linear_scan() {
+        let mut rng = Lcg(7);
+        for mismatches in 0..3 {
+            let mut bcs: Vec<Vec<u8>> = (0..24).map(|_| { let mut b = b"NNN".to_vec(); b.extend(rng.seq(5, b"ACGT")); b.extend_from_slice(b"NN"); b }).collect();
+            bcs.extend((0..6).map(|_| rng.seq(6, b"ACGT")));
+            bcs.push(bcs[0].clone());
+            let refs: Vec<&[u8]> = bcs.iter().map(|b| b.as_slice()).collect();
+            let prefix = BarcodeIndex::new(&refs, Anchor::Prefix, mismatches);
+            let suffix = BarcodeIndex::new(&refs, Anchor::Suffix, mismatches);
+            for n in 0..3000 {
+                let mut read = if n % 2 == 0 { bcs[(rng.next() as usize) % bcs.len()].iter().map(|&b| if b == b'N' { b'A' } else { b }).collect() } else { Vec::new() };
+                read.extend(rng.seq(12, b"ACGTNa"));
+                let len = (rng.next() as usize) % read.len();
+                let mut read = read[..len.max(4)].to_vec();
+                for _ in 0..(rng.next() % 3) { let p = (rng.next() as usize) % read.len(); read[p] = b"ACGTN"[(rng.next() as usize) % 5]; }
+                let expected = match best_prefix_match(&read, &refs, mismatches) { BarcodeHit::Unique(i) => Some(Some(i)), BarcodeHit::Ambiguous => Some(None), BarcodeHit::NoMatch => None };
+                let got = match prefix.best(&read) { BarcodeHit::Unique(i) => Some(Some(i)), BarcodeHit::Ambiguous => Some(None), BarcodeHit::NoMatch => None };
+                assert_eq!(got, expected, "prefix {:?}", String::from_utf8_lossy(&read));
+                let expected_first = refs.iter().position(|bc| suffix_match(&read, bc, mismatches));
+                assert_eq!(suffix.first(&read), expected_first, "suffix {:?}", String::from_utf8_lossy(&read));
+            }
+        }
+    }
+
+    #[test]
+    fn test_oversized_neighbourhood_falls_back_to_linear_scan() {
+        let mut rng = Lcg(11);
+        let bcs: Vec<Vec<u8>> = (0..12).map(|_| rng.seq(24, b"ACGT")).collect();
+        let refs: Vec<&[u8]> = bcs.iter().map(|b| b.as_slice()).collect();
+        let prefix = BarcodeIndex::new(&refs, Anchor::Prefix, 4);
+        let suffix = BarcodeIndex::new(&refs, Anchor::Suffix, 4);
+        assert!(prefix.tables.is_empty() && prefix.linear.is_some());
+        for n in 0..500 {
+            let mut read = bcs[n % bcs.len()].clone();
+            for _ in 0..(rng.next() % 7) { let p = (rng.next() as usize) % read.len(); read[p] = b"ACGTN"[(rng.next() as usize) % 5]; }
+            let mut long = rng.seq(5, b"ACGT");
+            long.extend_from_slice(&read);
+            read.extend(rng.seq(5, b"ACGT"));
+            let expected = match best_prefix_match(&read, &refs, 4) { BarcodeHit::Unique(i) => Some(Some(i)), BarcodeHit::Ambiguous => Some(None), BarcodeHit::NoMatch => None };
+            let got = match prefix.best(&read) { BarcodeHit::Unique(i) => Some(Some(i)), BarcodeHit::Ambiguous => Some(None), BarcodeHit::NoMatch => None };
+            assert_eq!(got, expected);
+            assert_eq!(suffix.first(&long), refs.iter().position(|bc| suffix_match(&long, bc, 4)));
+        }
+        assert!(!BarcodeIndex::new(&refs, Anchor::Prefix, 2).tables.is_empty());
+    }
+
+    #[test]
+    fn test_window_picks_fewest_mismatches_then_leftmost() {
+        let bcs: [&[u8]; 2] = [b"ACGTAC", b"TTGCAA"];
+        let index = BarcodeIndex::new(&bcs, Anchor::Prefix, 1);
+        assert!(matches!(index.best_in_window(b"GGACGTACTTT", 3), (BarcodeHit::Unique(0), 2)));
+        assert!(matches!(index.best_in_window(b"GACGTTCTTGCAATT", 6), (BarcodeHit::Unique(0), 1)));
+        assert!(matches!(index.best_in_window(b"GACGTTCTTGCAATT", 0), (BarcodeHit::NoMatch, 0)));
+        assert!(matches!(index.best_in_window(b"GACGTTCTTGCAAAC", 7), (BarcodeHit::Unique(1), 7)));
+    }
+}
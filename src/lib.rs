@@ -2,5 +2,14 @@ pub mod trim;
 pub mod demux;
 pub mod cli;
 pub mod align;
+pub mod stats;
+pub mod compress;
+pub mod sam;
+pub mod error;
+pub mod validate;
+pub mod index;
+pub mod samplesheet;
+pub mod read_structure;
+pub mod filter;
 
 pub use trim::{quality_trim_index, nextseq_trim_index};
\ No newline at end of file
@@ -0,0 +1,121 @@
+use std::str::FromStr;
+use crate::error::UltraplexError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SegmentKind { Template, SampleBarcode, MolecularBarcode, Skip }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment { pub kind: SegmentKind, pub len: Option<usize> }
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReadStructure { pub segments: Vec<Segment> }
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Extracted {
+    pub template: Vec<u8>,
+    pub template_qual: Vec<u8>,
+    pub sample_barcode: Vec<u8>,
+    pub sample_barcode_qual: Vec<u8>,
+    pub umi: Vec<u8>,
+    pub umi_qual: Vec<u8>,
+}
+
+impl FromStr for ReadStructure {
+    type Err = UltraplexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |message: String| UltraplexError::InvalidReadStructure { structure: s.to_string(), message };
+        let mut segments = Vec::new();
+        let mut rest = s.trim();
+        while !rest.is_empty() {
+            let (len, tail) = if let Some(tail) = rest.strip_prefix('+') { (None, tail) } else {
+                let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+                if digits == 0 { return Err(invalid(format!("expected a length or '+' at '{}'", rest))); }
+                let len: usize = rest[..digits].parse().map_err(|_| invalid(format!("bad length '{}'", &rest[..digits])))?;
+                if len == 0 { return Err(invalid("segment lengths must be positive".to_string())); }
+                (Some(len), &rest[digits..])
+            };
+            let kind = match tail.chars().next() {
+                Some('T') => SegmentKind::Template,
+                Some('B') => SegmentKind::SampleBarcode,
+                Some('M') => SegmentKind::MolecularBarcode,
+                Some('S') => SegmentKind::Skip,
+                other => return Err(invalid(format!("unknown segment type {:?} (expected T, B, M or S)", other))),
+            };
+            segments.push(Segment { kind, len });
+            rest = &tail[1..];
+        }
+        if segments.is_empty() { return Err(invalid("empty read structure".to_string())); }
+        if segments.iter().filter(|seg| seg.len.is_none()).count() > 1 { return Err(invalid("only one segment may have variable length '+'".to_string())); }
+        Ok(ReadStructure { segments })
+    }
+}
+
+impl std::fmt::Display for ReadStructure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for seg in self.segments.iter() {
+            match seg.len { Some(n) => write!(f, "{}", n)?, None => write!(f, "+")? }
+            write!(f, "{}", match seg.kind { SegmentKind::Template => 'T', SegmentKind::SampleBarcode => 'B', SegmentKind::MolecularBarcode => 'M', SegmentKind::Skip => 'S' })?;
+        }
+        Ok(())
+    }
+}
+
+impl ReadStructure {
+    pub fn fixed_len(&self) -> usize { self.segments.iter().filter_map(|seg| seg.len).sum() }
+
+    pub fn has_kind(&self, kind: SegmentKind) -> bool { self.segments.iter().any(|seg| seg.kind == kind) }
+
+    pub fn extract(&self, seq: &[u8], qual: &[u8]) -> Option<Extracted> {
+        let fixed = self.fixed_len();
+        if seq.len() < fixed { return None; }
+        let mut out = Extracted::default();
+        let mut start = 0;
+        for seg in self.segments.iter() {
+            let len = seg.len.unwrap_or(seq.len() - fixed);
+            let (s, q) = (&seq[start..start + len], &qual[start.min(qual.len())..(start + len).min(qual.len())]);
+            let (to_seq, to_qual) = match seg.kind {
+                SegmentKind::Template => (&mut out.template, &mut out.template_qual),
+                SegmentKind::SampleBarcode => (&mut out.sample_barcode, &mut out.sample_barcode_qual),
+                SegmentKind::MolecularBarcode => (&mut out.umi, &mut out.umi_qual),
+                SegmentKind::Skip => { start += len; continue; }
+            };
+            to_seq.extend_from_slice(s);
+            to_qual.extend_from_slice(q);
+            start += len;
+        }
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        let rs: ReadStructure = "4M8B6M+T".parse().unwrap();
+        assert_eq!(rs.segments.len(), 4);
+        assert_eq!(rs.fixed_len(), 18);
+        assert_eq!(rs.to_string(), "4M8B6M+T");
+        assert_eq!("+T10B".parse::<ReadStructure>().unwrap().segments[1], Segment { kind: SegmentKind::SampleBarcode, len: Some(10) });
+        assert!("+T+B".parse::<ReadStructure>().is_err());
+        assert!("4X".parse::<ReadStructure>().is_err());
+        assert!("0T".parse::<ReadStructure>().is_err());
+        assert!("".parse::<ReadStructure>().is_err());
+    }
+
+    #[test]
+    fn test_extract_segments() {
+        let rs: ReadStructure = "2M3B1S+T2B".parse().unwrap();
+        let e = rs.extract(b"AACCCGTTTTTGG", b"abcdefghijklm").unwrap();
+        assert_eq!(e.umi, b"AA");
+        assert_eq!(e.umi_qual, b"ab");
+        assert_eq!(e.sample_barcode, b"CCCGG");
+        assert_eq!(e.sample_barcode_qual, b"cdelm");
+        assert_eq!(e.template, b"TTTTT");
+        assert_eq!(e.template_qual, b"ghijk");
+        assert_eq!(rs.extract(b"AACCCGTT", b"abcdefgh").unwrap().template, b"");
+        assert!(rs.extract(b"AACCCGT", b"abcdefg").is_none());
+    }
+}
@@ -0,0 +1,98 @@
+use std::io::Write;
+
+pub const FLAG_PAIRED: u16 = 0x1;
+pub const FLAG_UNMAPPED: u16 = 0x4;
+pub const FLAG_MATE_UNMAPPED: u16 = 0x8;
+pub const FLAG_FIRST: u16 = 0x40;
+pub const FLAG_SECOND: u16 = 0x80;
+
+pub struct AuxTags<'a> {
+    pub umi: &'a [u8],
+    pub umi_qual: &'a [u8],
+    pub barcode: &'a [u8],
+    pub strand: &'a [u8],
+}
+
+impl AuxTags<'_> {
+    fn fields(&self) -> Vec<(&'static [u8; 2], u8, &[u8])> {
+        [(b"RX", b'Z', self.umi), (b"QX", b'Z', self.umi_qual), (b"BC", b'Z', self.barcode), (b"CB", b'Z', self.barcode), (b"XS", b'A', self.strand)]
+            .into_iter().filter(|(_, _, v)| !v.is_empty()).collect()
+    }
+}
+
+pub fn unmapped_flag(mate: usize, paired: bool) -> u16 {
+    if !paired { return FLAG_UNMAPPED; }
+    FLAG_PAIRED | FLAG_UNMAPPED | FLAG_MATE_UNMAPPED | if mate == 0 { FLAG_FIRST } else { FLAG_SECOND }
+}
+
+pub fn sam_header() -> Vec<u8> {
+    format!("@HD\tVN:1.6\tSO:unsorted\n@PG\tID:ultraplex_rs\tPN:ultraplex_rs\tVN:{}\n", env!("CARGO_PKG_VERSION")).into_bytes()
+}
+
+pub fn bam_header() -> Vec<u8> {
+    let text = sam_header();
+    let mut out = Vec::with_capacity(text.len() + 12);
+    out.extend_from_slice(b"BAM\x01");
+    out.extend_from_slice(&(text.len() as i32).to_le_bytes());
+    out.extend_from_slice(&text);
+    out.extend_from_slice(&0i32.to_le_bytes());
+    out
+}
+
+pub fn write_sam_record(w: &mut dyn Write, name: &[u8], flag: u16, seq: &[u8], qual: &[u8], tags: &AuxTags) -> anyhow::Result<()> {
+    w.write_all(name)?;
+    write!(w, "\t{}\t*\t0\t0\t*\t*\t0\t0\t", flag)?;
+    w.write_all(if seq.is_empty() { b"*" } else { seq })?;
+    w.write_all(b"\t")?;
+    w.write_all(if qual.is_empty() || qual.len() != seq.len() { b"*" } else { qual })?;
+    for (tag, kind, value) in tags.fields() {
+        w.write_all(b"\t")?;
+        w.write_all(tag)?;
+        w.write_all(&[b':', kind, b':'])?;
+        w.write_all(value)?;
+    }
+    w.write_all(b"\n")?;
+    Ok(())
+}
+
+fn nt16(b: u8) -> u8 {
+    match b.to_ascii_uppercase() {
+        b'=' => 0, b'A' => 1, b'C' => 2, b'M' => 3, b'G' => 4, b'R' => 5, b'S' => 6, b'V' => 7,
+        b'T' => 8, b'W' => 9, b'Y' => 10, b'H' => 11, b'K' => 12, b'D' => 13, b'B' => 14, _ => 15,
+    }
+}
+
+pub const MAX_READ_NAME: usize = 254;
+
+pub fn write_bam_record(w: &mut dyn Write, name: &[u8], flag: u16, seq: &[u8], qual: &[u8], tags: &AuxTags) -> anyhow::Result<()> {
+    if name.len() > MAX_READ_NAME { anyhow::bail!("read name {}... is {} bytes; BAM allows at most {}", String::from_utf8_lossy(&name[..32]), name.len(), MAX_READ_NAME); }
+    let mut rec: Vec<u8> = Vec::with_capacity(64 + name.len() + seq.len() * 2);
+    rec.extend_from_slice(&(-1i32).to_le_bytes());
+    rec.extend_from_slice(&(-1i32).to_le_bytes());
+    rec.push((name.len() + 1) as u8);
+    rec.push(0);
+    rec.extend_from_slice(&4680u16.to_le_bytes());
+    rec.extend_from_slice(&0u16.to_le_bytes());
+    rec.extend_from_slice(&flag.to_le_bytes());
+    rec.extend_from_slice(&(seq.len() as i32).to_le_bytes());
+    rec.extend_from_slice(&(-1i32).to_le_bytes());
+    rec.extend_from_slice(&(-1i32).to_le_bytes());
+    rec.extend_from_slice(&0i32.to_le_bytes());
+    rec.extend_from_slice(name);
+    rec.push(0);
+    for pair in seq.chunks(2) {
+        let hi = nt16(pair[0]) << 4;
+        let lo = if pair.len() > 1 { nt16(pair[1]) } else { 0 };
+        rec.push(hi | lo);
+    }
+    if qual.len() == seq.len() { rec.extend(qual.iter().map(|&q| q.saturating_sub(33))); } else { rec.extend(std::iter::repeat_n(0xffu8, seq.len())); }
+    for (tag, kind, value) in tags.fields() {
+        rec.extend_from_slice(tag);
+        rec.push(kind);
+        rec.extend_from_slice(value);
+        if kind == b'Z' { rec.push(0); }
+    }
+    w.write_all(&(rec.len() as i32).to_le_bytes())?;
+    w.write_all(&rec)?;
+    Ok(())
+}
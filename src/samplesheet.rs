@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use crate::demux::{BarcodeSet, check_bases, index_key};
+use crate::error::UltraplexError;
+
+const DATA_SECTIONS: [&str; 2] = ["[data]", "[bclconvert_data]"];
+const FIVE_PRIME_COLUMNS: [&str; 2] = ["inline_5p", "inline_barcode"];
+const THREE_PRIME_COLUMNS: [&str; 1] = ["inline_3p"];
+
+#[derive(Clone, Copy)]
+struct Columns { id: usize, five: Option<usize>, three: Option<usize>, index_1: Option<usize>, index_2: Option<usize> }
+
+pub fn looks_like_sample_sheet(content: &str) -> bool {
+    content.lines().map(|l| l.trim().trim_end_matches(',').to_lowercase()).any(|l| l == "[header]" || DATA_SECTIONS.contains(&l.as_str()))
+}
+
+fn column(header: &[String], names: &[&str]) -> Option<usize> { header.iter().position(|h| names.contains(&h.as_str())) }
+
+pub fn read_sample_sheet(path: &str) -> Result<BarcodeSet, UltraplexError> {
+    let content = std::fs::read_to_string(path).map_err(|source| UltraplexError::Io { path: path.to_string(), source })?;
+    parse_sample_sheet(path, &content)
+}
+
+pub fn parse_sample_sheet(path: &str, content: &str) -> Result<BarcodeSet, UltraplexError> {
+    let invalid = |line: u64, message: String| UltraplexError::InvalidSampleSheet { path: path.to_string(), line, message };
+    let mut five_prime: Vec<String> = Vec::new();
+    let mut three_prime: Vec<String> = Vec::new();
+    let mut linked: HashMap<String, Vec<String>> = HashMap::new();
+    let mut sample_names: HashMap<String, String> = HashMap::new();
+    let mut indexes: Vec<(String, String)> = Vec::new();
+    let mut key_lines: HashMap<String, u64> = HashMap::new();
+    let mut name_lines: HashMap<String, u64> = HashMap::new();
+    let mut layouts: Vec<((bool, bool, bool), u64)> = Vec::new();
+    let mut fivelength: Option<usize> = None;
+    let mut in_data = false;
+    let mut seen_data = false;
+    let mut header: Option<Columns> = None;
+    for (idx, raw) in content.lines().enumerate() {
+        let line = idx as u64 + 1;
+        let trimmed = raw.trim();
+        if trimmed.starts_with('[') {
+            let section = trimmed.trim_end_matches(',').to_lowercase();
+            in_data = DATA_SECTIONS.contains(&section.as_str());
+            seen_data |= in_data;
+            header = None;
+            continue;
+        }
+        if !in_data || trimmed.trim_matches(',').is_empty() { continue; }
+        let fields: Vec<String> = trimmed.split(',').map(|f| f.trim().to_string()).collect();
+        let cols = match header {
+            Some(h) => h,
+            None => {
+                let names: Vec<String> = fields.iter().map(|f| f.to_lowercase()).collect();
+                let id = column(&names, &["sample_id"]).ok_or_else(|| invalid(line, "data header has no Sample_ID column".to_string()))?;
+                let cols = Columns { id, five: column(&names, &FIVE_PRIME_COLUMNS), three: column(&names, &THREE_PRIME_COLUMNS), index_1: column(&names, &["index"]), index_2: column(&names, &["index2"]) };
+                if cols.five.is_none() && cols.three.is_none() && cols.index_1.is_none() { return Err(invalid(line, "data header has no index or Inline_5p/Inline_3p barcode column".to_string())); }
+                header = Some(cols);
+                continue;
+            }
+        };
+        let get = |c: Option<usize>| c.and_then(|c| fields.get(c)).map(|s| s.to_uppercase()).unwrap_or_default();
+        let name = fields.get(cols.id).cloned().unwrap_or_default();
+        if name.is_empty() { return Err(invalid(line, "empty Sample_ID".to_string())); }
+        let (five_bc, three_bc, i7, i5) = (get(cols.five), get(cols.three), get(cols.index_1), get(cols.index_2));
+        if five_bc.is_empty() && i7.is_empty() { return Err(invalid(line, format!("sample {} has neither an index nor a 5' inline barcode", name))); }
+        if five_bc.is_empty() && !three_bc.is_empty() { return Err(invalid(line, format!("sample {} has a 3' inline barcode but no 5' inline barcode", name))); }
+        if i7.is_empty() && !i5.is_empty() { return Err(invalid(line, format!("sample {} has index2 but no index", name))); }
+        for bc in [&five_bc, &three_bc, &i7, &i5] { check_bases(path, line, bc)?; }
+        layouts.push(((!five_bc.is_empty(), !i7.is_empty(), !i5.is_empty()), line));
+        if !five_bc.is_empty() {
+            let found = five_bc.replace('N', "").len();
+            match fivelength {
+                None => fivelength = Some(found),
+                Some(expected) if expected != found => return Err(UltraplexError::InconsistentBarcodeLength { path: path.to_string(), line, barcode: five_bc, expected, found }),
+                Some(_) => {}
+            }
+        }
+        let inline_key = if three_bc.is_empty() { five_bc.clone() } else { format!("5bc_{}_3bc_{}", five_bc, three_bc) };
+        let key = [index_key(&i7, &i5), inline_key].into_iter().filter(|k| !k.is_empty()).collect::<Vec<_>>().join("_");
+        if let Some(&first_line) = key_lines.get(&key) {
+            if sample_names.get(&key) == Some(&name) { continue; }
+            return Err(UltraplexError::DuplicateBarcode { path: path.to_string(), line, barcode: key, first_line });
+        }
+        key_lines.insert(key.clone(), line);
+        if let Some(&first_line) = name_lines.get(&name) { return Err(UltraplexError::DuplicateSampleName { path: path.to_string(), line, name, first_line }); }
+        name_lines.insert(name.clone(), line);
+        if !i7.is_empty() && !indexes.contains(&(i7.clone(), i5.clone())) { indexes.push((i7, i5)); }
+        if !five_bc.is_empty() && !five_prime.contains(&five_bc) { five_prime.push(five_bc.clone()); }
+        if !three_bc.is_empty() {
+            three_prime.push(three_bc.clone());
+            linked.entry(five_bc).or_default().push(three_bc);
+        }
+        sample_names.insert(key, name);
+    }
+    if !seen_data { return Err(invalid(0, "no [Data] or [BCLConvert_Data] section".to_string())); }
+    if let Some(&(_, line)) = layouts.iter().find(|(l, _)| *l != layouts[0].0) {
+        return Err(invalid(line, "all samples must use the same combination of index, index2 and inline 5' barcode".to_string()));
+    }
+    five_prime.sort();
+    three_prime.sort(); three_prime.dedup();
+    indexes.sort();
+    Ok(BarcodeSet { five_prime, three_prime, linked, sample_names, indexes, three_p_mismatches: 0, five_p_mismatches: 0, index_mismatches: 0, index_2_mismatches: 0 })
+}
@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use crate::filter::FilterReason;
+
+#[derive(Debug, Clone, Default)]
+pub struct DemuxStats {
+    pub total: u64,
+    pub no_match: u64,
+    pub ambiguous: u64,
+    pub too_short: u64,
+    pub low_quality: u64,
+    pub too_many_n: u64,
+    pub expected_errors: u64,
+    pub too_long: u64,
+    pub low_complexity: u64,
+    pub ignored_no_match: u64,
+    pub adapter_trimmed: u64,
+    pub low_posterior: u64,
+    pub reverse_strand: u64,
+    pub by_sample: BTreeMap<String, u64>,
+    pub posterior_sum: BTreeMap<String, f64>,
+}
+
+impl DemuxStats {
+    pub fn record(&mut self, key: &str, filtered: Option<FilterReason>, adapter_trimmed: bool, posterior: Option<f64>, low_posterior: bool, reverse_strand: bool) {
+        self.total += 1;
+        if adapter_trimmed { self.adapter_trimmed += 1; }
+        if low_posterior { self.low_posterior += 1; }
+        if reverse_strand { self.reverse_strand += 1; }
+        if key == "__skip__" { self.ignored_no_match += 1; return; }
+        if let Some(reason) = filtered {
+            *match reason {
+                FilterReason::TooShort => &mut self.too_short,
+                FilterReason::LowQuality => &mut self.low_quality,
+                FilterReason::TooManyN => &mut self.too_many_n,
+                FilterReason::ExpectedErrors => &mut self.expected_errors,
+                FilterReason::TooLong => &mut self.too_long,
+                FilterReason::LowComplexity => &mut self.low_complexity,
+            } += 1;
+            return;
+        }
+        match key {
+            "no_match" => self.no_match += 1,
+            "ambiguous" => self.ambiguous += 1,
+            _ => {
+                *self.by_sample.entry(key.to_string()).or_insert(0) += 1;
+                if let Some(p) = posterior { *self.posterior_sum.entry(key.to_string()).or_insert(0.0) += p; }
+            }
+        }
+    }
+
+    pub fn merge(mut self, other: DemuxStats) -> DemuxStats {
+        self.total += other.total;
+        self.no_match += other.no_match;
+        self.ambiguous += other.ambiguous;
+        self.too_short += other.too_short;
+        self.low_quality += other.low_quality;
+        self.too_many_n += other.too_many_n;
+        self.expected_errors += other.expected_errors;
+        self.too_long += other.too_long;
+        self.low_complexity += other.low_complexity;
+        self.ignored_no_match += other.ignored_no_match;
+        self.adapter_trimmed += other.adapter_trimmed;
+        self.low_posterior += other.low_posterior;
+        self.reverse_strand += other.reverse_strand;
+        for (k, v) in other.by_sample { *self.by_sample.entry(k).or_insert(0) += v; }
+        for (k, v) in other.posterior_sum { *self.posterior_sum.entry(k).or_insert(0.0) += v; }
+        self
+    }
+
+    pub fn assigned(&self) -> u64 { self.by_sample.values().sum() }
+
+    pub fn mean_posterior(&self, key: &str) -> Option<f64> {
+        self.posterior_sum.get(key).zip(self.by_sample.get(key)).map(|(s, &n)| s / n as f64)
+    }
+
+    pub fn summary(&self) -> String {
+        let pct = |n: u64| if self.total == 0 { 0.0 } else { 100.0 * n as f64 / self.total as f64 };
+        let mut out = String::new();
+        let _ = writeln!(out, "Total reads processed: {}", self.total);
+        let _ = writeln!(out, "Reads with adapter trimmed: {} ({:.2}%)", self.adapter_trimmed, pct(self.adapter_trimmed));
+        let _ = writeln!(out, "Assigned to samples: {} ({:.2}%)", self.assigned(), pct(self.assigned()));
+        if self.reverse_strand > 0 { let _ = writeln!(out, "  matched on the reverse strand: {}", self.reverse_strand); }
+        for (k, v) in self.by_sample.iter() {
+            match self.mean_posterior(k) {
+                Some(p) => { let _ = writeln!(out, "  {}\t{}\t{:.2}%\tmean posterior {:.4}", k, v, pct(*v), p); }
+                None => { let _ = writeln!(out, "  {}\t{}\t{:.2}%", k, v, pct(*v)); }
+            }
+        }
+        let _ = writeln!(out, "No match: {} ({:.2}%)", self.no_match, pct(self.no_match));
+        let _ = writeln!(out, "Ambiguous: {} ({:.2}%)", self.ambiguous, pct(self.ambiguous));
+        if self.low_posterior > 0 { let _ = writeln!(out, "  of which below posterior threshold: {}", self.low_posterior); }
+        for (label, n) in [("min_mean_quality", self.low_quality), ("max_n", self.too_many_n), ("max_expected_errors", self.expected_errors), ("max_length", self.too_long), ("low complexity", self.low_complexity)] {
+            if n > 0 { let _ = writeln!(out, "Dropped by {}: {} ({:.2}%)", label, n, pct(n)); }
+        }
+        let _ = writeln!(out, "Dropped by final_min_length: {} ({:.2}%)", self.too_short, pct(self.too_short));
+        let _ = writeln!(out, "Skipped by ignore_no_match: {} ({:.2}%)", self.ignored_no_match, pct(self.ignored_no_match));
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\n");
+        let _ = writeln!(out, "  \"total_reads\": {},", self.total);
+        let _ = writeln!(out, "  \"adapter_trimmed\": {},", self.adapter_trimmed);
+        let _ = writeln!(out, "  \"assigned\": {},", self.assigned());
+        let _ = writeln!(out, "  \"no_match\": {},", self.no_match);
+        let _ = writeln!(out, "  \"ambiguous\": {},", self.ambiguous);
+        let _ = writeln!(out, "  \"too_short\": {},", self.too_short);
+        let _ = writeln!(out, "  \"low_quality\": {},", self.low_quality);
+        let _ = writeln!(out, "  \"too_many_n\": {},", self.too_many_n);
+        let _ = writeln!(out, "  \"expected_errors\": {},", self.expected_errors);
+        let _ = writeln!(out, "  \"too_long\": {},", self.too_long);
+        let _ = writeln!(out, "  \"low_complexity\": {},", self.low_complexity);
+        let _ = writeln!(out, "  \"ignored_no_match\": {},", self.ignored_no_match);
+        let _ = writeln!(out, "  \"low_posterior\": {},", self.low_posterior);
+        let _ = writeln!(out, "  \"reverse_strand\": {},", self.reverse_strand);
+        out.push_str("  \"samples\": {");
+        let samples: Vec<String> = self.by_sample.iter().map(|(k, v)| format!("\n    {}: {}", json_string(k), v)).collect();
+        out.push_str(&samples.join(","));
+        out.push_str(if samples.is_empty() { "},\n" } else { "\n  },\n" });
+        out.push_str("  \"mean_posterior\": {");
+        let posteriors: Vec<String> = self.by_sample.keys().filter_map(|k| self.mean_posterior(k).map(|p| format!("\n    {}: {:.6}", json_string(k), p))).collect();
+        out.push_str(&posteriors.join(","));
+        out.push_str(if posteriors.is_empty() { "}\n}\n" } else { "\n  }\n}\n" });
+        out
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -38,6 +38,94 @@ pub fn nextseq_trim_index(sequence: &str, qualities: &str, cutoff: i32, base: i3
     max_i
 }
 
+pub fn poly_tail_trim_index(sequence: &[u8], base: u8, min_length: usize, max_error_rate: f64) -> usize {
+    let (mut score, mut best_score, mut errors) = (0i64, 0i64, 0usize);
+    let mut best = sequence.len();
+    for i in (0..sequence.len()).rev() {
+        if sequence[i] == base { score += 1; } else { score -= 2; errors += 1; }
+        if score > best_score && errors as f64 <= max_error_rate * (sequence.len() - i) as f64 { best_score = score; best = i; }
+    }
+    if sequence.len() - best < min_length { sequence.len() } else { best }
+}
+
+pub trait QualityTrimmer: Send + Sync {
+    fn trim(&self, seq: &[u8], qual: &[u8], base: i32) -> Option<(usize, usize)>;
+}
+
+pub struct RunningSum { pub cutoff_front: i32, pub cutoff_back: i32 }
+
+impl QualityTrimmer for RunningSum {
+    fn trim(&self, _seq: &[u8], qual: &[u8], base: i32) -> Option<(usize, usize)> {
+        Some(quality_trim_index(std::str::from_utf8(qual).unwrap_or(""), self.cutoff_front, self.cutoff_back, base))
+    }
+}
+
+pub struct NextSeq { pub cutoff: i32 }
+
+impl QualityTrimmer for NextSeq {
+    fn trim(&self, seq: &[u8], qual: &[u8], base: i32) -> Option<(usize, usize)> {
+        Some((0, nextseq_trim_index(std::str::from_utf8(seq).unwrap_or(""), std::str::from_utf8(qual).unwrap_or(""), self.cutoff, base)))
+    }
+}
+
+pub struct Leading { pub min_quality: i32 }
+
+impl QualityTrimmer for Leading {
+    fn trim(&self, _seq: &[u8], qual: &[u8], base: i32) -> Option<(usize, usize)> {
+        Some((qual.iter().position(|&q| q as i32 - base >= self.min_quality).unwrap_or(qual.len()), qual.len()))
+    }
+}
+
+pub struct Trailing { pub min_quality: i32 }
+
+impl QualityTrimmer for Trailing {
+    fn trim(&self, _seq: &[u8], qual: &[u8], base: i32) -> Option<(usize, usize)> {
+        Some((0, qual.iter().rposition(|&q| q as i32 - base >= self.min_quality).map_or(0, |i| i + 1)))
+    }
+}
+
+pub struct SlidingWindow { pub window: usize, pub min_quality: i32 }
+
+impl QualityTrimmer for SlidingWindow {
+    fn trim(&self, _seq: &[u8], qual: &[u8], base: i32) -> Option<(usize, usize)> {
+        let window = self.window.max(1);
+        if qual.len() < window { return Some((0, qual.len())); }
+        let required = self.min_quality as i64 * window as i64;
+        let mut total: i64 = qual[..window].iter().map(|&q| q as i64 - base as i64).sum();
+        for start in 0..=qual.len() - window {
+            if start > 0 { total += qual[start + window - 1] as i64 - qual[start - 1] as i64; }
+            if total < required {
+                let stop = start + qual[start..start + window].iter().position(|&q| (q as i32 - base) < self.min_quality).unwrap_or(0);
+                return Some((0, stop));
+            }
+        }
+        Some((0, qual.len()))
+    }
+}
+
+pub struct MinMeanQuality { pub min_mean: f64 }
+
+impl QualityTrimmer for MinMeanQuality {
+    fn trim(&self, _seq: &[u8], qual: &[u8], base: i32) -> Option<(usize, usize)> {
+        if qual.is_empty() { return None; }
+        let mean = qual.iter().map(|&q| (q as i32 - base) as f64).sum::<f64>() / qual.len() as f64;
+        if mean < self.min_mean { None } else { Some((0, qual.len())) }
+    }
+}
+
+pub struct TrimChain(pub Vec<Box<dyn QualityTrimmer>>);
+
+impl QualityTrimmer for TrimChain {
+    fn trim(&self, seq: &[u8], qual: &[u8], base: i32) -> Option<(usize, usize)> {
+        let (mut start, mut stop) = (0, qual.len().min(seq.len()));
+        for step in self.0.iter() {
+            let (s, e) = step.trim(&seq[start..stop], &qual[start..stop], base)?;
+            (start, stop) = (start + s, start + e.max(s));
+        }
+        if start >= stop { Some((0, 0)) } else { Some((start, stop)) }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -63,4 +151,28 @@ mod tests {
         let idx = nextseq_trim_index(seq, quals, 20, 33);
         assert!(idx <= quals.len());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_sliding_window_cuts_at_first_low_window() {
+        let quals = "IIIIIIII!!II";
+        assert_eq!(SlidingWindow { window: 4, min_quality: 30 }.trim(&[b'A'; 12], quals.as_bytes(), 33), Some((0, 8)));
+        assert_eq!(SlidingWindow { window: 4, min_quality: 20 }.trim(&[b'A'; 8], b"IIIIIIII", 33), Some((0, 8)));
+    }
+
+    #[test]
+    fn test_leading_trailing_and_mean_chain() {
+        let chain = TrimChain(vec![Box::new(Leading { min_quality: 3 }), Box::new(Trailing { min_quality: 3 }), Box::new(MinMeanQuality { min_mean: 20.0 })]);
+        assert_eq!(chain.trim(b"ACGTACGT", b"##IIII##", 33), Some((2, 6)));
+        assert_eq!(chain.trim(b"ACGTACGT", b"##+++###", 33), None);
+        assert_eq!(chain.trim(b"ACGT", b"####", 33), None);
+        assert_eq!(TrimChain(vec![Box::new(Leading { min_quality: 3 })]).trim(b"ACGT", b"####", 33), Some((0, 0)));
+    }
+
+    #[test]
+    fn test_poly_tail_tolerates_errors_and_respects_min_length() {
+        assert_eq!(poly_tail_trim_index(b"CCGTTCGGGGGGTGGGGG", b'G', 5, 0.1), 6);
+        assert_eq!(poly_tail_trim_index(b"CCGTTCAAAAAAAAAAAC", b'A', 5, 0.1), 6);
+        assert_eq!(poly_tail_trim_index(b"CCGTTCAAA", b'A', 5, 0.1), 9);
+        assert_eq!(poly_tail_trim_index(b"CCGTTCATAGAAA", b'A', 5, 0.1), 13);
+    }
+}
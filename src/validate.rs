@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use crate::align::Anchor;
+use crate::demux::BarcodeSet;
+
+pub fn fixed_hamming(a: &[u8], b: &[u8], anchor: Anchor) -> usize {
+    let n = a.len().min(b.len());
+    let (a, b) = match anchor { Anchor::Prefix => (&a[..n], &b[..n]), Anchor::Suffix => (&a[a.len() - n..], &b[b.len() - n..]) };
+    a.iter().zip(b.iter()).filter(|(&x, &y)| x != b'N' && y != b'N' && x != y).count()
+}
+
+#[derive(Debug, Clone)]
+pub struct GroupReport {
+    pub label: String,
+    pub n_barcodes: usize,
+    pub min_distance: Option<usize>,
+    pub closest: Option<(String, String)>,
+    pub mismatches: usize,
+    pub collisions: Vec<(String, String, usize)>,
+}
+
+impl GroupReport {
+    pub fn max_safe_mismatches(&self) -> Option<usize> { self.min_distance.map(|d| d.saturating_sub(1) / 2) }
+}
+
+pub fn check_group(label: &str, barcodes: &[String], anchor: Anchor, mismatches: usize) -> GroupReport {
+    let mut min_distance: Option<usize> = None;
+    let mut closest = None;
+    let mut collisions = Vec::new();
+    for i in 0..barcodes.len() {
+        for j in (i + 1)..barcodes.len() {
+            let d = fixed_hamming(barcodes[i].as_bytes(), barcodes[j].as_bytes(), anchor);
+            if min_distance.is_none_or(|m| d < m) { min_distance = Some(d); closest = Some((barcodes[i].clone(), barcodes[j].clone())); }
+            if d <= 2 * mismatches { collisions.push((barcodes[i].clone(), barcodes[j].clone(), d)); }
+        }
+    }
+    GroupReport { label: label.to_string(), n_barcodes: barcodes.len(), min_distance, closest, mismatches, collisions }
+}
+
+#[derive(Debug, Clone)]
+pub struct ValidationReport {
+    pub groups: Vec<GroupReport>,
+    pub duplicate_sample_names: Vec<String>,
+}
+
+impl ValidationReport {
+    pub fn is_safe(&self) -> bool { self.duplicate_sample_names.is_empty() && self.groups.iter().all(|g| g.collisions.is_empty()) }
+
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        for g in self.groups.iter() {
+            let _ = write!(out, "{}: {} barcodes", g.label, g.n_barcodes);
+            match (&g.min_distance, &g.closest) {
+                (Some(d), Some((a, b))) => { let _ = writeln!(out, ", min Hamming distance {} ({} vs {}), safe up to {} mismatches, using {}", d, a, b, g.max_safe_mismatches().unwrap_or(0), g.mismatches); }
+                _ => { let _ = writeln!(out); }
+            }
+            for (a, b, d) in g.collisions.iter() {
+                let _ = writeln!(out, "  COLLISION: {} vs {} differ at {} fixed positions; a read within {} mismatches can match both", a, b, d, g.mismatches);
+            }
+        }
+        for name in self.duplicate_sample_names.iter() { let _ = writeln!(out, "DUPLICATE SAMPLE NAME: {}", name); }
+        let _ = writeln!(out, "{}", if self.is_safe() { "Barcode set OK" } else { "Barcode set has problems at the requested mismatch levels" });
+        out
+    }
+}
+
+pub fn validate_barcodes(bcs: &BarcodeSet) -> ValidationReport {
+    let mut groups = vec![check_group("5' barcodes", &bcs.five_prime, Anchor::Prefix, bcs.five_p_mismatches)];
+    let mut keys: Vec<&String> = bcs.linked.keys().collect();
+    keys.sort();
+    for five in keys {
+        groups.push(check_group(&format!("3' barcodes linked to {}", five), &bcs.linked[five], Anchor::Suffix, bcs.three_p_mismatches));
+    }
+    if !bcs.indexes.is_empty() {
+        for (label, i, mismatches) in [("index 1 (I1)", 0, bcs.index_mismatches), ("index 2 (I2)", 1, bcs.index_2_mismatches)] {
+            let mut seqs: Vec<String> = bcs.indexes.iter().map(|p| if i == 0 { p.0.clone() } else { p.1.clone() }).filter(|s| !s.is_empty()).collect();
+            seqs.sort(); seqs.dedup();
+            if !seqs.is_empty() { groups.push(check_group(label, &seqs, Anchor::Prefix, mismatches)); }
+        }
+    }
+    let mut counts: HashMap<&String, usize> = HashMap::new();
+    for name in bcs.sample_names.values() { *counts.entry(name).or_insert(0) += 1; }
+    let mut duplicate_sample_names: Vec<String> = counts.into_iter().filter(|(_, c)| *c > 1).map(|(n, _)| n.clone()).collect();
+    duplicate_sample_names.sort();
+    ValidationReport { groups, duplicate_sample_names }
+}
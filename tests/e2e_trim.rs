@@ -2,10 +2,10 @@
 
 use std::fs;
 use std::io::Write;
-use flate2::read::GzDecoder;
+use flate2::read::{GzDecoder, MultiGzDecoder};
 use std::io::Read;
-use ultraplex_rs::cli::{Args, run};
-use ultraplex_rs::align::prefix_match;
+use ultraplex_rs::cli::{Args, QualityBase, RecordFormat, SheetFormat, TrimMode, run};
+use ultraplex_rs::align::{prefix_match, extract_umi_from_prefix};
 
 #[test]
 fn e2e_trim_small_fastq() {
@@ -17,7 +17,7 @@ fn e2e_trim_small_fastq() {
     writeln!(f, "+").unwrap();
     writeln!(f, "IIIIIIII").unwrap();
 
-    let args = Args { inputfastq: input.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: String::new(), outputprefix: "demux".to_string(), nextseq: false, gzip: false, three_prime_only: false, input_2: String::new(), threeprimemismatches: 0, threads: 1, keep_barcode: false, final_min_length: 0, ignore_no_match: false, phredquality: 30 };
+    let args = Args { inputfastq: input.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: String::new(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
     run(args).unwrap();
 
     let out = dir.path().join("ultraplex_demux_no_match.fastq");
@@ -32,7 +32,7 @@ fn e2e_test_simple_single_end_named_if_available() {
     let barcodes = root.join("tests/test_simple/barcodes_5_and_3_named.csv");
     if !(reads.exists() && barcodes.exists()) { return; }
     let out_dir = tempfile::tempdir().unwrap();
-    let args = Args { inputfastq: reads.to_str().unwrap().to_string(), directory: out_dir.path().to_str().unwrap().to_string(), barcodes: barcodes.to_str().unwrap().to_string(), outputprefix: "single_end_named".to_string(), nextseq: false, gzip: true, three_prime_only: false, input_2: String::new(), threeprimemismatches: 0, threads: 2, keep_barcode: false, final_min_length: 0, ignore_no_match: false, phredquality: 30 };
+    let args = Args { inputfastq: reads.to_str().unwrap().to_string(), directory: out_dir.path().to_str().unwrap().to_string(), barcodes: barcodes.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "single_end_named".to_string(), nextseq: false, gzip: true, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 2, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
     run(args).unwrap();
     let files = std::fs::read_dir(out_dir.path()).unwrap().map(|e| e.unwrap().path()).collect::<Vec<_>>();
     assert!(files.iter().any(|p| p.file_name().unwrap().to_string_lossy().contains("ultraplex_single_end_named_")));
@@ -45,7 +45,7 @@ fn e2e_test_simple_length_filter_if_available() {
     let barcodes = root.join("tests/test_simple/barcodes_5_and_3.csv");
     if !(reads.exists() && barcodes.exists()) { return; }
     let out_dir = tempfile::tempdir().unwrap();
-    let args = Args { inputfastq: reads.to_str().unwrap().to_string(), directory: out_dir.path().to_str().unwrap().to_string(), barcodes: barcodes.to_str().unwrap().to_string(), outputprefix: "single_end_length60".to_string(), nextseq: false, gzip: true, three_prime_only: false, input_2: String::new(), threeprimemismatches: 0, threads: 2, keep_barcode: false, final_min_length: 60, ignore_no_match: false, phredquality: 30 };
+    let args = Args { inputfastq: reads.to_str().unwrap().to_string(), directory: out_dir.path().to_str().unwrap().to_string(), barcodes: barcodes.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "single_end_length60".to_string(), nextseq: false, gzip: true, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 2, batch_size: 1024, keep_barcode: false, final_min_length: 60, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
     run(args).unwrap();
     let nm = out_dir.path().join("ultraplex_single_end_length60_no_match.fastq.gz");
     assert!(nm.exists());
@@ -65,7 +65,7 @@ fn e2e_demux_with_barcodes_and_gzip() {
     let mut bf = fs::File::create(&bcsv).unwrap();
     writeln!(bf, "ACGT").unwrap();
 
-    let args = Args { inputfastq: input.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), outputprefix: "demux".to_string(), nextseq: false, gzip: true, three_prime_only: false, input_2: String::new(), threeprimemismatches: 0, threads: 1, keep_barcode: false, final_min_length: 0, ignore_no_match: false, phredquality: 30 };
+    let args = Args { inputfastq: input.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: true, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
     run(args).unwrap();
 
     let out_bc = dir.path().join("ultraplex_demux_ACGT.fastq.gz");
@@ -75,20 +75,15 @@ fn e2e_demux_with_barcodes_and_gzip() {
     gz.read_to_string(&mut content).unwrap();
     assert!(content.contains("@r1"));
     let lines: Vec<&str> = content.lines().collect();
-    assert!(prefix_match(lines[1].as_bytes(), b"ACGT", 0));
+    assert_eq!(lines[1], "GGGG");
+    assert_eq!(lines[3], "IIII");
 }
 
 #[test]
 fn e2e_three_prime_only_with_umi_and_naming() {
     let dir = tempfile::tempdir().unwrap();
-    let r1 = dir.path().join("r1.fastq");
     let r2 = dir.path().join("r2.fastq");
     {
-        let mut f1 = fs::File::create(&r1).unwrap();
-        writeln!(f1, "@r1").unwrap();
-        writeln!(f1, "TTTTCCCC").unwrap();
-        writeln!(f1, "+").unwrap();
-        writeln!(f1, "IIIIIIII").unwrap();
         let mut f2 = fs::File::create(&r2).unwrap();
         writeln!(f2, "@r2").unwrap();
         writeln!(f2, "ACGTNNNN").unwrap();
@@ -102,7 +97,7 @@ fn e2e_three_prime_only_with_umi_and_naming() {
         writeln!(bf, "ACGT,NNNN:sampleX").unwrap();
     }
 
-    let args = Args { inputfastq: r2.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), outputprefix: "demux".to_string(), nextseq: false, gzip: true, three_prime_only: true, input_2: r1.to_str().unwrap().to_string(), threeprimemismatches: 0, threads: 1, keep_barcode: false, final_min_length: 0, ignore_no_match: false, phredquality: 30 };
+    let args = Args { inputfastq: r2.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: true, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: true, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
     run(args).unwrap();
 
     let out = dir.path().join("ultraplex_demux_sampleX.fastq.gz");
@@ -111,4 +106,779 @@ fn e2e_three_prime_only_with_umi_and_naming() {
     let mut content = String::new();
     gz.read_to_string(&mut content).unwrap();
     assert!(content.contains("rbc:"));
-}
\ No newline at end of file
+}
+#[test]
+fn e2e_paired_end_fwd_rev_outputs() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    let r2 = dir.path().join("r2.fastq");
+    {
+        let mut f1 = fs::File::create(&r1).unwrap();
+        writeln!(f1, "@p1/1").unwrap();
+        writeln!(f1, "ACGTAAAA").unwrap();
+        writeln!(f1, "+").unwrap();
+        writeln!(f1, "IIIIIIII").unwrap();
+        writeln!(f1, "@p2/1").unwrap();
+        writeln!(f1, "TTTTAAAA").unwrap();
+        writeln!(f1, "+").unwrap();
+        writeln!(f1, "IIIIIIII").unwrap();
+        let mut f2 = fs::File::create(&r2).unwrap();
+        writeln!(f2, "@p1/2").unwrap();
+        writeln!(f2, "CCCCGGGG").unwrap();
+        writeln!(f2, "+").unwrap();
+        writeln!(f2, "IIIIIIII").unwrap();
+        writeln!(f2, "@p2/2").unwrap();
+        writeln!(f2, "GGGGCCCC").unwrap();
+        writeln!(f2, "+").unwrap();
+        writeln!(f2, "IIIIIIII").unwrap();
+    }
+    let bcsv = dir.path().join("barcodes.csv");
+    {
+        let mut bf = fs::File::create(&bcsv).unwrap();
+        writeln!(bf, "ACGT").unwrap();
+    }
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: r2.to_str().unwrap().to_string(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args).unwrap();
+
+    let fwd = fs::read_to_string(dir.path().join("ultraplex_demux_ACGT_Fwd.fastq")).unwrap();
+    let rev = fs::read_to_string(dir.path().join("ultraplex_demux_ACGT_Rev.fastq")).unwrap();
+    assert!(fwd.contains("@p1/1") && !fwd.contains("@p2"));
+    assert!(rev.contains("@p1/2\nCCCCGGGG"));
+    let nm_rev = fs::read_to_string(dir.path().join("ultraplex_demux_no_match_Rev.fastq")).unwrap();
+    assert!(nm_rev.contains("@p2/2"));
+    assert!(!dir.path().join("ultraplex_demux_no_match.fastq").exists());
+}
+
+#[test]
+fn e2e_paired_end_rejects_mismatched_mates() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    let r2 = dir.path().join("r2.fastq");
+    {
+        let mut f1 = fs::File::create(&r1).unwrap();
+        writeln!(f1, "@a/1").unwrap();
+        writeln!(f1, "ACGT").unwrap();
+        writeln!(f1, "+").unwrap();
+        writeln!(f1, "IIII").unwrap();
+        let mut f2 = fs::File::create(&r2).unwrap();
+        writeln!(f2, "@b/2").unwrap();
+        writeln!(f2, "ACGT").unwrap();
+        writeln!(f2, "+").unwrap();
+        writeln!(f2, "IIII").unwrap();
+    }
+    let mk = |input_2: &std::path::Path| Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: String::new(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: input_2.to_str().unwrap().to_string(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    assert!(run(mk(&r2)).is_err());
+
+    let r2_long = dir.path().join("r2_long.fastq");
+    {
+        let mut f2 = fs::File::create(&r2_long).unwrap();
+        for name in ["a/2", "c/2"] {
+            writeln!(f2, "@{}", name).unwrap();
+            writeln!(f2, "ACGT").unwrap();
+            writeln!(f2, "+").unwrap();
+            writeln!(f2, "IIII").unwrap();
+        }
+    }
+    assert!(run(mk(&r2_long)).is_err());
+}
+
+#[test]
+fn e2e_five_prime_umi_extracted_and_clipped() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("small.fastq");
+    let mut f = fs::File::create(&input).unwrap();
+    writeln!(f, "@r1").unwrap();
+    writeln!(f, "CAGATGTCTTTTAAAA").unwrap();
+    writeln!(f, "+").unwrap();
+    writeln!(f, "IIIIIIIIIIIIIIII").unwrap();
+
+    let bcsv = dir.path().join("barcodes.csv");
+    let mut bf = fs::File::create(&bcsv).unwrap();
+    writeln!(bf, "NNNATGNN,").unwrap();
+    writeln!(bf, "NNNCCGNN,").unwrap();
+
+    let args = Args { inputfastq: input.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args).unwrap();
+
+    let content = fs::read_to_string(dir.path().join("ultraplex_demux_NNNATGNN.fastq")).unwrap();
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(lines[0], "@r1rbc:CAGTC");
+    assert_eq!(lines[1], "TTTTAAAA");
+    assert_eq!(extract_umi_from_prefix(b"CAGATGTC", b"NNNATGNN").unwrap(), b"CAGTC".to_vec());
+    assert!(prefix_match(b"CAGATGTC", b"NNNATGNN", 0));
+}
+
+#[test]
+fn e2e_three_prime_only_concatenates_umis() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("r.fastq");
+    let mut f = fs::File::create(&input).unwrap();
+    writeln!(f, "@r1").unwrap();
+    writeln!(f, "GTACGCCCCCCTTGA").unwrap();
+    writeln!(f, "+").unwrap();
+    writeln!(f, "IIIIIIIIIIIIIII").unwrap();
+
+    let bcsv = dir.path().join("barcodes.csv");
+    let mut bf = fs::File::create(&bcsv).unwrap();
+    writeln!(bf, "CGTNC,NNTGA:s1").unwrap();
+
+    let args = Args { inputfastq: input.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: true, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args).unwrap();
+
+    let content = fs::read_to_string(dir.path().join("ultraplex_demux_s1.fastq")).unwrap();
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(lines[0], "@r1rbc:TCT");
+    assert_eq!(lines[1], "CCCCC");
+}
+
+#[test]
+fn e2e_five_prime_mismatches_and_ambiguous() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("small.fastq");
+    let mut f = fs::File::create(&input).unwrap();
+    for (name, seq) in [("exact", "ACGTCCCC"), ("onemm", "TCGTCCCC"), ("tie", "ACGCCCCC"), ("far", "TTTTCCCC")] {
+        writeln!(f, "@{}", name).unwrap();
+        writeln!(f, "{}", seq).unwrap();
+        writeln!(f, "+").unwrap();
+        writeln!(f, "IIIIIIII").unwrap();
+    }
+
+    let bcsv = dir.path().join("barcodes.csv");
+    let mut bf = fs::File::create(&bcsv).unwrap();
+    writeln!(bf, "ACGT").unwrap();
+    writeln!(bf, "ACGA").unwrap();
+
+    let args = Args { inputfastq: input.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 1, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args).unwrap();
+
+    let acgt = fs::read_to_string(dir.path().join("ultraplex_demux_ACGT.fastq")).unwrap();
+    assert!(acgt.contains("@exact") && acgt.contains("@onemm"));
+    let amb = fs::read_to_string(dir.path().join("ultraplex_demux_ambiguous.fastq")).unwrap();
+    assert!(amb.contains("@tie") && !amb.contains("@exact"));
+    let nm = fs::read_to_string(dir.path().join("ultraplex_demux_no_match.fastq")).unwrap();
+    assert!(nm.contains("@far"));
+}
+
+#[test]
+fn e2e_adapter_removed_before_three_prime_barcode() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("r.fastq");
+    let mut f = fs::File::create(&input).unwrap();
+    writeln!(f, "@r1").unwrap();
+    writeln!(f, "ACGTCCCCCCGGTTCAGATCGGAAG").unwrap();
+    writeln!(f, "+").unwrap();
+    writeln!(f, "IIIIIIIIIIIIIIIIIIIIIIIII").unwrap();
+
+    let bcsv = dir.path().join("barcodes.csv");
+    let mut bf = fs::File::create(&bcsv).unwrap();
+    writeln!(bf, "ACGT,NNTTC:s1").unwrap();
+
+    let args = Args { inputfastq: input.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: true, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args).unwrap();
+
+    let content = fs::read_to_string(dir.path().join("ultraplex_demux_s1.fastq")).unwrap();
+    let lines: Vec<&str> = content.lines().collect();
+    assert_eq!(lines[0], "@r1rbc:GG");
+    assert_eq!(lines[1], "CCCCCC");
+}
+
+#[test]
+fn e2e_stats_json_counts_outcomes() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("small.fastq");
+    let mut f = fs::File::create(&input).unwrap();
+    for (name, seq) in [("a1", "ACGTCCCCCC"), ("a2", "ACGTCCCCCC"), ("short", "ACGTCC"), ("nm", "TTTTCCCCCC")] {
+        writeln!(f, "@{}", name).unwrap();
+        writeln!(f, "{}", seq).unwrap();
+        writeln!(f, "+").unwrap();
+        writeln!(f, "{}", "I".repeat(seq.len())).unwrap();
+    }
+
+    let bcsv = dir.path().join("barcodes.csv");
+    let mut bf = fs::File::create(&bcsv).unwrap();
+    writeln!(bf, "ACGT").unwrap();
+
+    let args = Args { inputfastq: input.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 2, batch_size: 1024, keep_barcode: false, final_min_length: 4, ignore_no_match: true, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args).unwrap();
+
+    let json = fs::read_to_string(dir.path().join("ultraplex_demux.stats.json")).unwrap();
+    assert!(json.contains("\"total_reads\": 4,"));
+    assert!(json.contains("\"too_short\": 1,"));
+    assert!(json.contains("\"ignored_no_match\": 1,"));
+    assert!(json.contains("\"ACGT\": 2"));
+}
+
+#[test]
+fn e2e_small_batches_keep_output_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("many.fastq");
+    let mut f = fs::File::create(&input).unwrap();
+    for i in 0..200 {
+        let seq = if i % 3 == 0 { "TTTTCCCC" } else { "ACGTCCCC" };
+        writeln!(f, "@read{}", i).unwrap();
+        writeln!(f, "{}", seq).unwrap();
+        writeln!(f, "+").unwrap();
+        writeln!(f, "IIIIIIII").unwrap();
+    }
+
+    let bcsv = dir.path().join("barcodes.csv");
+    let mut bf = fs::File::create(&bcsv).unwrap();
+    writeln!(bf, "ACGT").unwrap();
+
+    let args = Args { inputfastq: input.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: true, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 4, batch_size: 7, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args).unwrap();
+
+    let mut gz = GzDecoder::new(fs::File::open(dir.path().join("ultraplex_demux_ACGT.fastq.gz")).unwrap());
+    let mut content = String::new();
+    gz.read_to_string(&mut content).unwrap();
+    let names: Vec<String> = content.lines().step_by(4).map(|l| l.to_string()).collect();
+    let expected: Vec<String> = (0..200).filter(|i| i % 3 != 0).map(|i| format!("@read{}", i)).collect();
+    assert_eq!(names, expected);
+}
+
+#[test]
+fn e2e_missing_input_is_an_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let args = Args { inputfastq: dir.path().join("absent.fastq").to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: String::new(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    assert!(run(args).is_err());
+}
+
+#[test]
+fn e2e_parallel_gzip_multi_member_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("big.fastq");
+    let mut f = std::io::BufWriter::new(fs::File::create(&input).unwrap());
+    let body = "ACGTTGCA".repeat(12);
+    for i in 0..6000 {
+        writeln!(f, "@read{}", i).unwrap();
+        writeln!(f, "ACGT{}", body).unwrap();
+        writeln!(f, "+").unwrap();
+        writeln!(f, "{}", "I".repeat(body.len() + 4)).unwrap();
+    }
+    drop(f);
+
+    let bcsv = dir.path().join("barcodes.csv");
+    let mut bf = fs::File::create(&bcsv).unwrap();
+    writeln!(bf, "ACGT").unwrap();
+
+    let args = Args { inputfastq: input.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: true, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 1, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 3, batch_size: 500, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args).unwrap();
+
+    let mut gz = MultiGzDecoder::new(fs::File::open(dir.path().join("ultraplex_demux_ACGT.fastq.gz")).unwrap());
+    let mut content = String::new();
+    gz.read_to_string(&mut content).unwrap();
+    let names: Vec<&str> = content.lines().step_by(4).collect();
+    assert_eq!(names.len(), 6000);
+    assert_eq!(names[0], "@read0");
+    assert_eq!(names[5999], "@read5999");
+    assert!(content.lines().nth(1).unwrap() == body);
+
+    let mut empty = MultiGzDecoder::new(fs::File::open(dir.path().join("ultraplex_demux_no_match.fastq.gz")).unwrap());
+    let mut nm = String::new();
+    empty.read_to_string(&mut nm).unwrap();
+    assert!(nm.is_empty());
+}
+
+#[test]
+fn e2e_bgzip_output_is_valid_bgzf() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("big.fastq");
+    let mut f = std::io::BufWriter::new(fs::File::create(&input).unwrap());
+    for i in 0..3000 {
+        writeln!(f, "@read{}", i).unwrap();
+        writeln!(f, "ACGTACGTTGCAACGTTGCAAACCGGTTACGTTGCAACGTTGCAAACCGGTT").unwrap();
+        writeln!(f, "+").unwrap();
+        writeln!(f, "{}", "I".repeat(52)).unwrap();
+    }
+    drop(f);
+
+    let args = Args { inputfastq: input.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: String::new(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: true, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 2, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args).unwrap();
+
+    let bytes = fs::read(dir.path().join("ultraplex_demux_no_match.fastq.gz")).unwrap();
+    let mut pos = 0usize;
+    let mut blocks = 0usize;
+    while pos < bytes.len() {
+        assert_eq!(&bytes[pos..pos + 4], &[0x1f, 0x8b, 0x08, 0x04]);
+        assert_eq!(&bytes[pos + 12..pos + 16], &[b'B', b'C', 0x02, 0x00]);
+        let bsize = u16::from_le_bytes([bytes[pos + 16], bytes[pos + 17]]) as usize;
+        pos += bsize + 1;
+        blocks += 1;
+    }
+    assert_eq!(pos, bytes.len());
+    assert!(blocks > 2);
+    assert_eq!(&bytes[bytes.len() - 28..], &ultraplex_rs::compress::BGZF_EOF);
+
+    let mut gz = MultiGzDecoder::new(&bytes[..]);
+    let mut content = String::new();
+    gz.read_to_string(&mut content).unwrap();
+    assert_eq!(content.lines().count(), 3000 * 4);
+}
+
+#[test]
+fn e2e_sam_output_with_umi_and_barcode_tags() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    let r2 = dir.path().join("r2.fastq");
+    {
+        let mut f1 = fs::File::create(&r1).unwrap();
+        writeln!(f1, "@p1/1").unwrap();
+        writeln!(f1, "CAGATGTCTTTTAAAA").unwrap();
+        writeln!(f1, "+").unwrap();
+        writeln!(f1, "ABCDEFGHIIIIIIII").unwrap();
+        let mut f2 = fs::File::create(&r2).unwrap();
+        writeln!(f2, "@p1/2").unwrap();
+        writeln!(f2, "GGGGCCCC").unwrap();
+        writeln!(f2, "+").unwrap();
+        writeln!(f2, "IIIIIIII").unwrap();
+    }
+    let bcsv = dir.path().join("barcodes.csv");
+    let mut bf = fs::File::create(&bcsv).unwrap();
+    writeln!(bf, "NNNATGNN").unwrap();
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Sam, compression_level: 6, three_prime_only: false, input_2: r2.to_str().unwrap().to_string(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args).unwrap();
+
+    let sam = fs::read_to_string(dir.path().join("ultraplex_demux_NNNATGNN.sam")).unwrap();
+    let lines: Vec<&str> = sam.lines().filter(|l| !l.starts_with('@')).collect();
+    assert!(sam.starts_with("@HD\tVN:1.6"));
+    assert_eq!(lines[0], "p1\t77\t*\t0\t0\t*\t*\t0\t0\tTTTTAAAA\tIIIIIIII\tRX:Z:CAGTC\tQX:Z:ABCGH\tBC:Z:ATG\tCB:Z:ATG");
+    assert!(lines[1].starts_with("p1\t141\t"));
+    assert!(!dir.path().join("ultraplex_demux_NNNATGNN_Fwd.sam").exists());
+}
+
+#[test]
+fn e2e_bam_output_is_unaligned_bam() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("r.fastq");
+    let mut f = fs::File::create(&input).unwrap();
+    writeln!(f, "@r1 extra").unwrap();
+    writeln!(f, "ACGTGGCAT").unwrap();
+    writeln!(f, "+").unwrap();
+    writeln!(f, "IIIIIIII5").unwrap();
+    let bcsv = dir.path().join("barcodes.csv");
+    let mut bf = fs::File::create(&bcsv).unwrap();
+    writeln!(bf, "ACGT").unwrap();
+
+    let args = Args { inputfastq: input.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Bam, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 0, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args.clone()).unwrap();
+
+    let bytes = fs::read(dir.path().join("ultraplex_demux_ACGT.bam")).unwrap();
+    assert_eq!(&bytes[bytes.len() - 28..], &ultraplex_rs::compress::BGZF_EOF);
+    let mut raw = Vec::new();
+    MultiGzDecoder::new(&bytes[..]).read_to_end(&mut raw).unwrap();
+    assert_eq!(&raw[..4], b"BAM\x01");
+    let l_text = i32::from_le_bytes(raw[4..8].try_into().unwrap()) as usize;
+    let mut p = 8 + l_text;
+    assert_eq!(i32::from_le_bytes(raw[p..p + 4].try_into().unwrap()), 0);
+    p += 4;
+    let block_size = i32::from_le_bytes(raw[p..p + 4].try_into().unwrap()) as usize;
+    let rec = &raw[p + 4..p + 4 + block_size];
+    assert_eq!(p + 4 + block_size, raw.len());
+    let l_name = rec[8] as usize;
+    let flag = u16::from_le_bytes([rec[14], rec[15]]);
+    let l_seq = i32::from_le_bytes(rec[16..20].try_into().unwrap()) as usize;
+    assert_eq!(flag, 4);
+    assert_eq!(&rec[32..32 + l_name], b"r1\0");
+    assert_eq!(l_seq, 5);
+    let seq_start = 32 + l_name;
+    assert_eq!(&rec[seq_start..seq_start + 3], &[0x44, 0x21, 0x80]);
+    assert_eq!(&rec[seq_start + 3..seq_start + 8], &[40, 40, 40, 40, 20]);
+    assert_eq!(&rec[seq_start + 8..], b"BCZACGT\0CBZACGT\0");
+
+    let long = dir.path().join("long.fastq");
+    fs::write(&long, format!("@{}\nACGTGGCAT\n+\nIIIIIIIII\n", "r".repeat(300))).unwrap();
+    assert!(run(Args { inputfastq: long.to_str().unwrap().to_string(), outputprefix: "long".to_string(), ..args }).is_err());
+}
+
+#[test]
+fn barcode_csv_errors_are_typed_with_line_numbers() {
+    use ultraplex_rs::demux::read_barcodes_csv;
+    use ultraplex_rs::error::UltraplexError;
+    let dir = tempfile::tempdir().unwrap();
+    let sheet = |name: &str, body: &str| {
+        let p = dir.path().join(name);
+        fs::write(&p, body).unwrap();
+        p.to_str().unwrap().to_string()
+    };
+
+    let ok = read_barcodes_csv(&sheet("ok.csv", "NNNATGNN,\nNNNCCGNN,ATG:sample2,TCA:sample3,\nNNNCACNN,\n")).unwrap();
+    assert_eq!(ok.five_prime.len(), 3);
+    assert_eq!(ok.sample_names.len(), 2);
+    assert!(read_barcodes_csv(&sheet("five_name.csv", "ACGT:s1\nTGCA\n")).unwrap().sample_names.is_empty());
+
+    match read_barcodes_csv(&sheet("len.csv", "NNNATGNN\nNNNATGCNN\n")) {
+        Err(UltraplexError::InconsistentBarcodeLength { line: 2, expected: 3, found: 4, .. }) => {}
+        other => panic!("unexpected {:?}", other.err()),
+    }
+    match read_barcodes_csv(&sheet("base.csv", "ACGT\nACXT\n")) {
+        Err(UltraplexError::InvalidBase { line: 2, base: 'X', .. }) => {}
+        other => panic!("unexpected {:?}", other.err()),
+    }
+    match read_barcodes_csv(&sheet("dupname.csv", "ACGT,AAA:s1\nTGCA,CCC:s1\n")) {
+        Err(UltraplexError::DuplicateSampleName { line: 2, first_line: 1, .. }) => {}
+        other => panic!("unexpected {:?}", other.err()),
+    }
+    match read_barcodes_csv(&sheet("dupthree.csv", "TGCA\nACGT,AAA,CCC:s2,AAA\n")) {
+        Err(e @ UltraplexError::DuplicateThreePrimeBarcode { line: 2, column: 4, first_column: 2, .. }) => assert!(e.to_string().ends_with("3' barcode AAA is listed in both column 2 and column 4 of this row")),
+        other => panic!("unexpected {:?}", other.err()),
+    }
+    match read_barcodes_csv(&sheet("dupbc.csv", "ACGT\nTGCA\nACGT\n")) {
+        Err(UltraplexError::DuplicateBarcode { line: 3, first_line: 1, .. }) => {}
+        other => panic!("unexpected {:?}", other.err()),
+    }
+    match read_barcodes_csv(dir.path().join("missing.csv").to_str().unwrap()) {
+        Err(UltraplexError::Csv { .. }) => {}
+        other => panic!("unexpected {:?}", other.err()),
+    }
+}
+
+#[test]
+fn validate_reports_distances_and_collisions() {
+    use ultraplex_rs::demux::read_barcodes_csv;
+    use ultraplex_rs::validate::validate_barcodes;
+    let dir = tempfile::tempdir().unwrap();
+    let p = dir.path().join("b.csv");
+    fs::write(&p, "NNNATGNN,AAAT:s1,AACT:s2\nNNNCCGNN,\nNNNCACNN,\n").unwrap();
+    let mut bcs = read_barcodes_csv(p.to_str().unwrap()).unwrap();
+
+    let report = validate_barcodes(&bcs);
+    assert_eq!(report.groups[0].min_distance, Some(2));
+    assert_eq!(report.groups[1].min_distance, Some(1));
+    assert!(report.is_safe());
+
+    bcs.five_p_mismatches = 1;
+    let report = validate_barcodes(&bcs);
+    assert!(!report.is_safe());
+    assert_eq!(report.groups[0].collisions.len(), 2);
+    assert!(report.summary().contains("COLLISION"));
+
+    bcs.sample_names.insert("5bc_NNNCCGNN_3bc_AAAT".to_string(), "s1".to_string());
+    assert_eq!(validate_barcodes(&bcs).duplicate_sample_names, vec!["s1".to_string()]);
+}
+
+#[test]
+fn e2e_illumina_sample_sheet_names_outputs() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    {
+        let mut f1 = fs::File::create(&r1).unwrap();
+        writeln!(f1, "@r1\nAAAATTTTGGGG\n+\nIIIIIIIIIIII").unwrap();
+        writeln!(f1, "@r2\nCCCCTTTTGGGG\n+\nIIIIIIIIIIII").unwrap();
+    }
+    let sheet = dir.path().join("SampleSheet.csv");
+    fs::write(&sheet, "[Header],,\nFileFormatVersion,2,\n\n[BCLConvert_Settings],,\nAdapterRead1,AGATCGGAAGAGC,\n\n[BCLConvert_Data],,\nLane,Sample_ID,Inline_5p\n1,liverA,AAAA\n1,liverB,cccc\n").unwrap();
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: sheet.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args.clone()).unwrap();
+    let a = fs::read_to_string(dir.path().join("ultraplex_demux_liverA.fastq")).unwrap();
+    let b = fs::read_to_string(dir.path().join("ultraplex_demux_liverB.fastq")).unwrap();
+    assert_eq!(a.lines().nth(1), Some("TTTTGGGG"));
+    assert!(b.starts_with("@r2\n"));
+    assert!(!dir.path().join("ultraplex_demux_AAAA.fastq").exists());
+
+    assert!(run(Args { sheet_format: SheetFormat::Ultraplex, ..args }).is_err());
+}
+
+#[test]
+fn sample_sheet_linked_barcodes_and_errors() {
+    use ultraplex_rs::samplesheet::parse_sample_sheet;
+    use ultraplex_rs::error::UltraplexError;
+    use ultraplex_rs::validate::validate_barcodes;
+    let bcs = parse_sample_sheet("s.csv", "[Data]\nSample_ID,Sample_Name,Inline_5p,Inline_3p\nS1,a,NNATGN,AAAT\nS2,b,NNATGN,CCGT\nS3,c,NNCCGN,\n").unwrap();
+    assert_eq!(bcs.five_prime, vec!["NNATGN".to_string(), "NNCCGN".to_string()]);
+    assert_eq!(bcs.linked["NNATGN"], vec!["AAAT".to_string(), "CCGT".to_string()]);
+    assert_eq!(bcs.sample_names["5bc_NNATGN_3bc_CCGT"], "S2");
+    assert_eq!(bcs.sample_names["NNCCGN"], "S3");
+
+    match parse_sample_sheet("s.csv", "[Data]\nLane,Inline_5p\n1,ACGT\n") {
+        Err(UltraplexError::InvalidSampleSheet { line: 2, .. }) => {}
+        other => panic!("unexpected {:?}", other.err()),
+    }
+    let lanes = parse_sample_sheet("s.csv", "[Data]\nLane,Sample_ID,index,Inline_5p\n1,S1,AAAAAAAA,ACGT\n2,S1,AAAAAAAA,ACGT\n1,S2,AAAAAAAA,TGCA\n2,S2,AAAAAAAA,TGCA\n").unwrap();
+    assert_eq!(lanes.five_prime, vec!["ACGT".to_string(), "TGCA".to_string()]);
+    assert_eq!(lanes.indexes, vec![("AAAAAAAA".to_string(), String::new())]);
+    assert_eq!(lanes.sample_names.len(), 2);
+    assert!(validate_barcodes(&lanes).duplicate_sample_names.is_empty());
+    match parse_sample_sheet("s.csv", "[Data]\nLane,Sample_ID,Inline_5p\n1,S1,ACGT\n2,S2,ACGT\n") {
+        Err(UltraplexError::DuplicateBarcode { line: 4, first_line: 3, .. }) => {}
+        other => panic!("unexpected {:?}", other.err()),
+    }
+    match parse_sample_sheet("s.csv", "[Data]\nSample_ID,Inline_5p\nS1,ACGT\nS1,TGCA\n") {
+        Err(UltraplexError::DuplicateSampleName { line: 4, first_line: 3, .. }) => {}
+        other => panic!("unexpected {:?}", other.err()),
+    }
+    match parse_sample_sheet("s.csv", "[Header]\nRunName,x\n") {
+        Err(UltraplexError::InvalidSampleSheet { .. }) => {}
+        other => panic!("unexpected {:?}", other.err()),
+    }
+}
+
+#[test]
+fn e2e_index_reads_combine_with_inline_barcodes() {
+    let dir = tempfile::tempdir().unwrap();
+    let write = |name: &str, reads: &[(&str, &str)]| {
+        let p = dir.path().join(name);
+        let mut f = fs::File::create(&p).unwrap();
+        for (id, seq) in reads { writeln!(f, "@{}\n{}\n+\n{}", id, seq, "I".repeat(seq.len())).unwrap(); }
+        p.to_str().unwrap().to_string()
+    };
+    let r1 = write("r1.fastq", &[("r1 1:N:0", "ACGTGGGG"), ("r2 1:N:0", "ACGTAAAA"), ("r3 1:N:0", "ACGTCCCC"), ("r4 1:N:0", "TGCATTTT")]);
+    let i1 = write("i1.fastq", &[("r1 3:N:0", "AAAAAAAT"), ("r2 3:N:0", "GGGGGGGG"), ("r3 3:N:0", "GGGGGGGG"), ("r4 3:N:0", "AAAAAAAA")]);
+    let i2 = write("i2.fastq", &[("r1 4:N:0", "CCCCCCCC"), ("r2 4:N:0", "TTTTTTTT"), ("r3 4:N:0", "CCCCCCCC"), ("r4 4:N:0", "CCCCCCCC")]);
+    let sheet = dir.path().join("SampleSheet.csv");
+    fs::write(&sheet, "[Data]\nSample_ID,index,index2,Inline_5p\nS1,AAAAAAAA,CCCCCCCC,ACGT\nS2,AAAAAAAA,CCCCCCCC,TGCA\nS3,GGGGGGGG,TTTTTTTT,ACGT\n").unwrap();
+
+    let args = Args { inputfastq: r1, directory: dir.path().to_str().unwrap().to_string(), barcodes: sheet.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: i1, index_2: i2, indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args.clone()).unwrap();
+    let read = |key: &str| fs::read_to_string(dir.path().join(format!("ultraplex_demux_{}.fastq", key))).unwrap();
+    assert_eq!(read("S1"), "@r1 1:N:0\nGGGG\n+\nIIII\n");
+    assert!(read("S2").starts_with("@r4 "));
+    assert!(read("S3").starts_with("@r2 "));
+    assert!(read("no_match").starts_with("@r3 "));
+
+    assert!(run(Args { indexmismatches: 0, outputprefix: "strict".to_string(), ..args.clone() }).is_ok());
+    assert!(fs::read_to_string(dir.path().join("ultraplex_strict_no_match.fastq")).unwrap().starts_with("@r1 "));
+    assert!(run(Args { index_2: String::new(), ..args }).is_err());
+}
+
+#[test]
+fn e2e_header_barcodes_and_umis() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    fs::write(&r1, "@M:1:FC:1:1:10:20:ACGTAC 1:N:0:AAAAAAAA+CCCCCCCT\nGGGGTTTT\n+\nIIIIIIII\n@M:1:FC:1:1:10:21 1:N:0:GGGGGGGG+TTTTTTTT\nCCCCAAAA\n+\nIIIIIIII\n@M:1:FC:1:1:10:22 1:N:0:AAAAAAAA\nCCCCAAAA\n+\nIIIIIIII\n").unwrap();
+    let sheet = dir.path().join("SampleSheet.csv");
+    fs::write(&sheet, "[BCLConvert_Data]\nSample_ID,index,index2\nS1,AAAAAAAA,CCCCCCCC\nS2,GGGGGGGG,TTTTTTTT\n").unwrap();
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: sheet.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 0, index2mismatches: Some(1), header_barcodes: true, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args.clone()).unwrap();
+    let read = |key: &str| fs::read_to_string(dir.path().join(format!("ultraplex_demux_{}.fastq", key))).unwrap();
+    assert_eq!(read("S1"), "@M:1:FC:1:1:10:20:ACGTAC 1:N:0:AAAAAAAA+CCCCCCCTrbc:ACGTAC\nGGGGTTTT\n+\nIIIIIIII\n");
+    assert_eq!(read("S2"), "@M:1:FC:1:1:10:21 1:N:0:GGGGGGGG+TTTTTTTT\nCCCCAAAA\n+\nIIIIIIII\n");
+    assert!(read("no_match").starts_with("@M:1:FC:1:1:10:22 "));
+
+    assert!(run(Args { index2mismatches: None, outputprefix: "strict".to_string(), ..args.clone() }).is_ok());
+    assert!(fs::read_to_string(dir.path().join("ultraplex_strict_no_match.fastq")).unwrap().starts_with("@M:1:FC:1:1:10:20:"));
+    assert!(run(Args { index_1: r1.to_str().unwrap().to_string(), ..args }).is_err());
+}
+
+#[test]
+fn e2e_read_structures_place_barcodes_and_umis() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    let r2 = dir.path().join("r2.fastq");
+    fs::write(&r1, "@p1/1\nGATCACGTTTTTCCCC\n+\nABCDEFGHIIIIIIII\n@p2/1\nGATCTTTTACGTACGT\n+\nIIIIIIIIIIIIIIII\n@p3/1\nGAT\n+\nIII\n").unwrap();
+    fs::write(&r2, "@p1/2\nAAAAGG\n+\nIIIIII\n@p2/2\nCCCCGG\n+\nIIIIII\n@p3/2\nTTTTGG\n+\nIIIIII\n").unwrap();
+    let bcsv = dir.path().join("barcodes.csv");
+    fs::write(&bcsv, "ACGT\n").unwrap();
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: r2.to_str().unwrap().to_string(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: "4M4B+T".to_string(), read_structure_2: "+T2S".to_string(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args.clone()).unwrap();
+
+    let fwd = fs::read_to_string(dir.path().join("ultraplex_demux_ACGT_Fwd.fastq")).unwrap();
+    let rev = fs::read_to_string(dir.path().join("ultraplex_demux_ACGT_Rev.fastq")).unwrap();
+    assert_eq!(fwd, "@p1/1rbc:GATC\nTTTTCCCC\n+\nIIIIIIII\n");
+    assert_eq!(rev, "@p1/2rbc:GATC\nAAAA\n+\nIIII\n");
+    let no_match = fs::read_to_string(dir.path().join("ultraplex_demux_no_match_Fwd.fastq")).unwrap();
+    assert!(no_match.starts_with("@p2/1rbc:GATC\nACGTACGT\n"));
+    assert!(no_match.contains("@p3/1\nGAT\n"));
+
+    assert!(run(Args { read_structure: "4M4X".to_string(), ..args.clone() }).is_err());
+    assert!(run(Args { read_structure: "4M+T".to_string(), read_structure_2: String::new(), ..args }).is_err());
+}
+
+#[test]
+fn e2e_edit_distance_clips_true_barcode_end() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    fs::write(&r1, "@r1\nTCGACGTCGGGGTTTT\n+\nABCDEFGHIIIIIIII\n@r2\nTCGACGTTACGGGGTTTT\n+\nIIIIIIIIIIIIIIIIII\n").unwrap();
+    let bcsv = dir.path().join("barcodes.csv");
+    fs::write(&bcsv, "NNNACGTAC\nNNNTTGCAA\n").unwrap();
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 1, edit_distance: true, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args.clone()).unwrap();
+    let out = fs::read_to_string(dir.path().join("ultraplex_demux_NNNACGTAC.fastq")).unwrap();
+    assert_eq!(out, "@r1rbc:TCG\nGGGGTTTT\n+\nIIIIIIII\n@r2rbc:TCG\nGGGGTTTT\n+\nIIIIIIII\n");
+
+    run(Args { edit_distance: false, outputprefix: "hamming".to_string(), ..args }).unwrap();
+    assert_eq!(fs::read_to_string(dir.path().join("ultraplex_hamming_no_match.fastq")).unwrap().lines().count(), 8);
+}
+
+#[test]
+fn e2e_posterior_threshold_uses_qualities() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    fs::write(&r1, "@hq\nACGTGGGG\n+\nIIIIIIII\n@lq\nACGTGGGG\n+\nIII#IIII\n@mm\nACGCGGGG\n+\nIIIIIIII\n").unwrap();
+    let bcsv = dir.path().join("barcodes.csv");
+    fs::write(&bcsv, "ACGT\nACGA\nTTTT\n").unwrap();
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 1, edit_distance: false, posterior_threshold: Some(0.99), five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 0, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args).unwrap();
+    assert_eq!(fs::read_to_string(dir.path().join("ultraplex_demux_ACGT.fastq")).unwrap(), "@hq\nGGGG\n+\nIIII\n");
+    let ambiguous = fs::read_to_string(dir.path().join("ultraplex_demux_ambiguous.fastq")).unwrap();
+    assert!(ambiguous.starts_with("@lq\n"));
+    assert!(ambiguous.contains("@mm\n"));
+    let json = fs::read_to_string(dir.path().join("ultraplex_demux.stats.json")).unwrap();
+    assert!(json.contains("\"low_posterior\": 2,"));
+    assert!(json.contains("\"mean_posterior\": {\n    \"ACGT\": 0.99"));
+}
+
+#[test]
+fn e2e_five_prime_window_finds_offset_barcodes() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    fs::write(&r1, "@r1\nTTTACGTGGGG\n+\nIIIIIIIIIII\n@r2\nCCAAAACGTCCCC\n+\nIIIIIIIIIIIII\n").unwrap();
+    let bcsv = dir.path().join("barcodes.csv");
+    fs::write(&bcsv, "NNNACGT\n").unwrap();
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 3, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args.clone()).unwrap();
+    assert_eq!(fs::read_to_string(dir.path().join("ultraplex_demux_NNNACGT.fastq")).unwrap(), "@r1rbc:TTT\nGGGG\n+\nIIII\n@r2rbc:AAA\nCCCC\n+\nIIII\n");
+
+    run(Args { five_prime_window: 0, outputprefix: "fixed".to_string(), ..args }).unwrap();
+    assert!(fs::read_to_string(dir.path().join("ultraplex_fixed_no_match.fastq")).unwrap().starts_with("@r2\n"));
+}
+
+#[test]
+fn e2e_detect_orientation_reorients_reverse_reads() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    fs::write(&r1, "@fwd\nTTTACGTGGGGCC\n+\nIIIIIIIIIIIII\n@rev\nTTGGGGACGTTTT\n+\n?@ABCDEFGHIJK\n@adapt\nGGTTCCAAGGACGTCCCAGATCGGAAGAGCGGTTCAG\n+\nIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII\n@none\nGGGGGGGGGGGGG\n+\nIIIIIIIIIIIII\n").unwrap();
+    let bcsv = dir.path().join("barcodes.csv");
+    fs::write(&bcsv, "NNNACGT\n").unwrap();
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "demux".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: true, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args.clone()).unwrap();
+    assert_eq!(fs::read_to_string(dir.path().join("ultraplex_demux_NNNACGT.fastq")).unwrap(), "@fwdrbc:TTT strand:+\nGGGGCC\n+\nIIIIII\n@revrbc:AAA strand:-\nCCCCAA\n+\nDCBA@?\n@adaptrbc:GGG strand:-\nCCTTGGAACC\n+\nIIIIIIIIII\n");
+    assert_eq!(fs::read_to_string(dir.path().join("ultraplex_demux_no_match.fastq")).unwrap(), "@none\nGGGGGGGGGGGGG\n+\nIIIIIIIIIIIII\n");
+    assert!(fs::read_to_string(dir.path().join("ultraplex_demux.stats.json")).unwrap().contains("\"reverse_strand\": 2,"));
+
+    run(Args { output_format: RecordFormat::Sam, ..args }).unwrap();
+    let sam = fs::read_to_string(dir.path().join("ultraplex_demux_NNNACGT.sam")).unwrap();
+    assert!(sam.lines().any(|l| l.starts_with("rev\t4\t") && l.ends_with("\tXS:A:-")));
+}
+
+#[test]
+fn e2e_phred64_is_detected_and_converted() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    fs::write(&r1, "@r1\nACGTGGGGCCCC\n+\nhhhhhhhhBBBB\n").unwrap();
+    let bcsv = dir.path().join("barcodes.csv");
+    fs::write(&bcsv, "ACGT\n").unwrap();
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "auto".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: "AGATCGGAAGAGCGGTTCAG".to_string(), adapter2: "AGATCGGAAGAGCGTCGTG".to_string(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Auto, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args.clone()).unwrap();
+    run(Args { outputprefix: "converted".to_string(), output_phred33: true, ..args.clone() }).unwrap();
+    run(Args { outputprefix: "forced".to_string(), quality_base: QualityBase::Phred33, ..args.clone() }).unwrap();
+    let read = |prefix: &str| fs::read_to_string(dir.path().join(format!("ultraplex_{}_ACGT.fastq", prefix))).unwrap();
+    assert_eq!(read("auto"), "@r1\nGGGG\n+\nhhhh\n");
+    assert_eq!(read("converted"), "@r1\nGGGG\n+\nIIII\n");
+    assert_eq!(read("forced"), "@r1\nGGGGCCCC\n+\nhhhhBBBB\n");
+    fs::write(&r1, "@r1\nACGTGGGGCCCC\n+\n??FFLLLLLLLL\n@r2\nACGTGGGGCCCC\n+\nFFFF????FFFF\n").unwrap();
+    run(Args { outputprefix: "binned".to_string(), quality_base: QualityBase::Auto, ..args }).unwrap();
+    assert_eq!(read("binned"), "@r1\nGGGGCCCC\n+\nLLLLLLLL\n@r2\nGGGGCCCC\n+\n????FFFF\n");
+}
+
+#[test]
+fn e2e_sliding_window_leading_and_mean_quality_trimmers() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    fs::write(&r1, "@dip\nACGTACGTACGTACGT\n+\nIIIIIIII!!!!IIII\n@lead\nTTACGTAC\n+\n##IIIIII\n@poor\nACGTACGT\n+\n++++++++\n").unwrap();
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: String::new(), sheet_format: SheetFormat::Auto, outputprefix: "cutadapt".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: String::new(), adapter2: String::new(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 0, quality_base: QualityBase::Phred33, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args.clone()).unwrap();
+    run(Args { outputprefix: "trimmomatic".to_string(), trimmer: TrimMode::SlidingWindow, phredquality: 20, leading: Some(3), min_mean_quality: Some(20.0), ..args.clone() }).unwrap();
+    assert!(run(Args { outputprefix: "bad".to_string(), nextseq: true, trimmer: TrimMode::SlidingWindow, ..args }).is_err());
+
+    let read = |prefix: &str| fs::read_to_string(dir.path().join(format!("ultraplex_{}_no_match.fastq", prefix))).unwrap();
+    assert_eq!(read("cutadapt"), "@dip\nACGTACGTACGTACGT\n+\nIIIIIIII!!!!IIII\n@lead\nTTACGTAC\n+\n##IIIIII\n@poor\nACGTACGT\n+\n++++++++\n");
+    assert_eq!(read("trimmomatic"), "@dip\nACGTACGT\n+\nIIIIIIII\n@lead\nACGTAC\n+\nIIIIII\n");
+    let json = fs::read_to_string(dir.path().join("ultraplex_trimmomatic.stats.json")).unwrap();
+    assert!(json.contains("\"low_quality\": 1,"));
+}
+
+#[test]
+fn e2e_poly_tail_trimmed_before_three_prime_barcode() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    let reads = [("tail", "ACGTCCGTTCCTTAAAAAAAAAAAA"), ("short", "ACGTCCGTTCCTTAAA"), ("error", "ACGTCCGTTCCTTAAAAAGAAAAAAAA")];
+    let body: String = reads.iter().map(|(n, s)| format!("@{}\n{}\n+\n{}\n", n, s, "I".repeat(s.len()))).collect();
+    fs::write(&r1, body).unwrap();
+    let bcsv = dir.path().join("barcodes.csv");
+    fs::write(&bcsv, "ACGT,CTT\n").unwrap();
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "poly".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: true, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: String::new(), adapter2: String::new(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Phred33, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: "a".to_string(), poly_tail_min_length: 5, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args.clone()).unwrap();
+    assert!(run(Args { poly_tail: "AG".to_string(), ..args.clone() }).is_err());
+    let out = fs::read_to_string(dir.path().join("ultraplex_poly_5bc_ACGT_3bc_CTT.fastq")).unwrap();
+    assert_eq!(out, "@tail\nCCGTTC\n+\nIIIIII\n@error\nCCGTTC\n+\nIIIIII\n");
+    let short = fs::read_to_string(dir.path().join("ultraplex_poly_no_match.fastq")).unwrap();
+    assert!(short.contains("\nACGTCCGTTCCTTAAA\n"));
+    let r2 = dir.path().join("r2.fastq");
+    fs::write(&r2, "@tail\nGGCATTCAAAAAAAAAAAA\n+\nIIIIIIIIIIIIIIIIIII\n").unwrap();
+    fs::write(&r1, "@tail\nACGTCCGTTCCTTAAAAAAAAAAAA\n+\nIIIIIIIIIIIIIIIIIIIIIIIII\n").unwrap();
+    run(Args { input_2: r2.to_str().unwrap().to_string(), outputprefix: "poly_pe".to_string(), ..args }).unwrap();
+    let fwd = fs::read_to_string(dir.path().join("ultraplex_poly_pe_5bc_ACGT_3bc_CTT_Fwd.fastq")).unwrap();
+    let rev = fs::read_to_string(dir.path().join("ultraplex_poly_pe_5bc_ACGT_3bc_CTT_Rev.fastq")).unwrap();
+    assert!(fwd.contains("\nCCGTTC\n"));
+    assert!(rev.contains("\nGGCATTCAAAAAAAAAAAA\n"));
+}
+
+#[test]
+fn e2e_read_filters_count_reasons_and_write_filtered() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    let reads = [("good", "ACGTACGTTGCAAGCT", "IIIIIIIIIIIIIIII"), ("ns", "ACGTACNTTGCNAGCT", "IIIIIIIIIIIIIIII"), ("errors", "ACGTACGTTGCAAGCT", "IIIIIIIIIIIII###"), ("long", "ACGTACGTTGCAAGCTAC", "IIIIIIIIIIIIIIIIII"), ("dust", "ACGTAAAAAAAAAAAA", "IIIIIIIIIIIIIIII")];
+    let body: String = reads.iter().map(|(n, s, q)| format!("@{}\n{}\n+\n{}\n", n, s, q)).collect();
+    fs::write(&r1, body).unwrap();
+    let bcsv = dir.path().join("barcodes.csv");
+    fs::write(&bcsv, "ACGT\n").unwrap();
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "filt".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: String::new(), adapter2: String::new(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 0, quality_base: QualityBase::Phred33, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: Some(0.1), max_expected_errors: Some(1.0), max_length: Some(12), max_dust: Some(2.0), min_entropy: None, write_filtered: true, tso: String::new(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args).unwrap();
+
+    assert_eq!(fs::read_to_string(dir.path().join("ultraplex_filt_ACGT.fastq")).unwrap(), "@good\nACGTTGCAAGCT\n+\nIIIIIIIIIIII\n");
+    let filtered = fs::read_to_string(dir.path().join("ultraplex_filt_filtered.fastq")).unwrap();
+    for head in ["@ns filter:too_many_n", "@errors filter:expected_errors", "@long filter:too_long", "@dust filter:low_complexity"] { assert!(filtered.contains(head), "{}", head); }
+    let json = fs::read_to_string(dir.path().join("ultraplex_filt.stats.json")).unwrap();
+    for field in ["\"too_many_n\": 1,", "\"expected_errors\": 1,", "\"too_long\": 1,", "\"low_complexity\": 1,", "\"assigned\": 1,"] { assert!(json.contains(field), "{}", field); }
+}
+
+#[test]
+fn e2e_tso_removed_after_five_prime_barcode() {
+    let dir = tempfile::tempdir().unwrap();
+    let r1 = dir.path().join("r1.fastq");
+    let reads = [("tso", "ACGTAAGCAGTGGTATCAACGCAGAGTACATGGGGCTTACGGA"), ("tso_error", "ACGTAAGCAGTGGTATCACGCAGAGTACATGGCTTACGGA"), ("plain", "ACGTCTTACGGA"), ("unmatched", "TTTTAAGCAGTGGTATCAACGCAGAGTACATGGGCTTACGGA")];
+    let body: String = reads.iter().map(|(n, s)| format!("@{}\n{}\n+\n{}\n", n, s, "I".repeat(s.len()))).collect();
+    fs::write(&r1, body).unwrap();
+    let bcsv = dir.path().join("barcodes.csv");
+    fs::write(&bcsv, "ACGT\n").unwrap();
+
+    let args = Args { inputfastq: r1.to_str().unwrap().to_string(), directory: dir.path().to_str().unwrap().to_string(), barcodes: bcsv.to_str().unwrap().to_string(), sheet_format: SheetFormat::Auto, outputprefix: "tso".to_string(), nextseq: false, gzip: false, bgzip: false, output_format: RecordFormat::Fastq, compression_level: 6, three_prime_only: false, input_2: String::new(), barcode_mate: 1, index_1: String::new(), index_2: String::new(), indexmismatches: 1, index2mismatches: None, header_barcodes: false, read_structure: String::new(), read_structure_2: String::new(), threeprimemismatches: 0, fiveprimemismatches: 0, edit_distance: false, posterior_threshold: None, five_prime_window: 0, detect_orientation: false, threads: 1, batch_size: 1024, keep_barcode: false, final_min_length: 0, ignore_no_match: false, adapter: String::new(), adapter2: String::new(), adapter_error_rate: 0.1, min_adapter_overlap: 3, phredquality: 30, quality_base: QualityBase::Phred33, output_phred33: false, trimmer: TrimMode::Cutadapt, window_size: 4, leading: None, trailing: None, min_mean_quality: None, poly_tail: String::new(), poly_tail_min_length: 10, poly_tail_error_rate: 0.1, max_n: None, max_expected_errors: None, max_length: None, max_dust: None, min_entropy: None, write_filtered: false, tso: "aagcagtggtatcaacgcagagtacatggg".to_string(), tso_mismatches: 2, tso_max_g: 5 };
+    run(args.clone()).unwrap();
+    assert!(run(Args { tso: "GGG".to_string(), ..args }).is_err());
+    let out = fs::read_to_string(dir.path().join("ultraplex_tso_ACGT.fastq")).unwrap();
+    assert_eq!(out, "@tso\nCTTACGGA\n+\nIIIIIIII\n@tso_error\nCTTACGGA\n+\nIIIIIIII\n@plain\nCTTACGGA\n+\nIIIIIIII\n");
+    let unmatched = fs::read_to_string(dir.path().join("ultraplex_tso_no_match.fastq")).unwrap();
+    assert!(unmatched.contains("\nTTTTAAGCAGTGGTATCAACGCAGAGTACATGGGCTTACGGA\n"));
+}
+
+#[test]
+fn adapter_trimming_is_on_by_default() {
+    let args = <Args as clap::Parser>::try_parse_from(["ultraplex_rs", "-i", "reads.fastq"]).unwrap();
+    assert_eq!((args.adapter.as_str(), args.adapter2.as_str()), ("AGATCGGAAGAGCGGTTCAG", "AGATCGGAAGAGCGTCGTG"));
+    assert!(<Args as clap::Parser>::try_parse_from(["ultraplex_rs", "-i", "reads.fastq", "-a", ""]).unwrap().adapter.is_empty());
+}
+
+#[test]
+fn validate_is_a_subcommand() {
+    use ultraplex_rs::cli::{Cli, Command};
+    let parse = |argv: &[&str]| <Cli as clap::Parser>::try_parse_from(argv);
+    let Cli { command: Some(Command::Validate(v)), args: None } = parse(&["ultraplex_rs", "validate", "-b", "barcodes.csv", "-m", "1"]).unwrap() else { panic!("expected validate") };
+    assert_eq!((v.barcodes.as_str(), v.fiveprimemismatches), ("barcodes.csv", 1));
+    assert!(matches!(parse(&["ultraplex_rs", "-i", "reads.fastq"]).unwrap(), Cli { command: None, args: Some(_) }));
+    assert_eq!(parse(&["ultraplex_rs", "validate", "--help"]).unwrap_err().kind(), clap::error::ErrorKind::DisplayHelp);
+    assert!(parse(&["ultraplex_rs", "validate"]).is_err());
+    assert!(parse(&["ultraplex_rs", "-i", "reads.fastq", "validate", "-b", "barcodes.csv"]).is_err());
+}